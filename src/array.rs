@@ -0,0 +1,315 @@
+use crate::{CdevBackend, Distance, EchoBackend, HcSr04, HcSr04Error, MIN_MEASUREMENT_CYCLE};
+use std::thread::sleep;
+use std::time::*;
+
+/// Order in which a [`SensorArray`] fires its sensors.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Schedule {
+    /// one sensor at a time, in the order they were added
+    #[default]
+    RoundRobin,
+    /// each group fires at once, groups take turns; a group's sensors must not
+    /// see each other's cones. Every sensor must be in exactly one group.
+    Groups(Vec<Vec<String>>),
+    /// explicit slots of sensors fired together, sensors may appear in several
+    /// slots or not at all; a repeated sensor reports its last reading
+    Custom(Vec<Vec<String>>),
+}
+
+/// One sensor's entry in a [`Frame`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FrameReading {
+    pub name: String,
+    /// when the sensor was triggered
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub at: Instant,
+    /// distance in cm
+    pub result: Result<Distance, HcSr04Error>,
+}
+
+/// Readings from one pass over the schedule. The `Instant`s are left out when
+/// serialized, so frames can't be deserialized.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Frame {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub started: Instant,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub finished: Instant,
+    /// in the order the sensors were added
+    pub readings: Vec<FrameReading>,
+}
+
+impl Frame {
+    pub fn get(&self, name: &str) -> Option<&FrameReading> {
+        self.readings.iter().find(|reading| reading.name == name)
+    }
+}
+
+struct Member<B: EchoBackend> {
+    name: String,
+    sensor: HcSr04<B>,
+    /// how long this sensor's echoes take to die down
+    guard: Duration,
+}
+
+/// Several sensors on one chassis, fired so they don't hear each other's pings.
+///
+/// Sensors in one slot are triggered back to back and their edges collected
+/// afterwards, which relies on kernel edge timestamps to stay accurate. The next
+/// slot only fires once the longest guard interval of the previous one is over.
+pub struct SensorArray<B: EchoBackend = CdevBackend> {
+    members: Vec<Member<B>>,
+    schedule: Schedule,
+    slots: Vec<Vec<usize>>,
+    next_fire: Option<Instant>,
+}
+
+impl<B: EchoBackend> Default for SensorArray<B> {
+    fn default() -> Self {
+        Self {
+            members: Vec::new(),
+            schedule: Schedule::RoundRobin,
+            slots: Vec::new(),
+            next_fire: None,
+        }
+    }
+}
+
+impl<B: EchoBackend> SensorArray<B> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sensor with the default guard of [`MIN_MEASUREMENT_CYCLE`].
+    pub fn add<S: Into<String>>(&mut self, name: S, sensor: HcSr04<B>) -> Result<(), HcSr04Error> {
+        self.add_with_guard(name, sensor, MIN_MEASUREMENT_CYCLE)
+    }
+
+    pub fn add_with_guard<S: Into<String>>(&mut self, name: S, sensor: HcSr04<B>, guard: Duration) -> Result<(), HcSr04Error> {
+        let name = name.into();
+        if self.index_of(&name).is_some() {
+            return Err(HcSr04Error::InvalidConfig(format!("sensor {:?} is already in the array", name)))
+        }
+        self.members.push(Member { name, sensor, guard });
+        if let Err(err) = self.resolve(self.schedule.clone()) {
+            self.members.pop();
+            return Err(err)
+        }
+        Ok(())
+    }
+
+    /// Add the sensors first. Fails if the schedule names unknown sensors, or if `Groups` doesn't
+    /// cover every sensor exactly once.
+    pub fn set_schedule(&mut self, schedule: Schedule) -> Result<(), HcSr04Error> {
+        self.resolve(schedule)
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|member| member.name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn sensor_mut(&mut self, name: &str) -> Option<&mut HcSr04<B>> {
+        let index = self.index_of(name)?;
+        Some(&mut self.members[index].sensor)
+    }
+
+    /// Takes the sensors back out, with their names.
+    pub fn into_sensors(self) -> Vec<(String, HcSr04<B>)> {
+        self.members.into_iter().map(|member| (member.name, member.sensor)).collect()
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.members.iter().position(|member| member.name == name)
+    }
+
+    fn resolve(&mut self, schedule: Schedule) -> Result<(), HcSr04Error> {
+        let lookup = |slots: &[Vec<String>]| -> Result<Vec<Vec<usize>>, HcSr04Error> {
+            slots.iter().map(|slot| slot.iter().map(|name| match self.index_of(name) {
+                Some(index) => Ok(index),
+                None => Err(HcSr04Error::InvalidConfig(format!("schedule names unknown sensor {:?}", name)))
+            }).collect()).collect()
+        };
+
+        let slots = match &schedule {
+            Schedule::RoundRobin => (0..self.members.len()).map(|index| vec![index]).collect(),
+            Schedule::Custom(slots) => lookup(slots)?,
+            Schedule::Groups(groups) => {
+                let slots = lookup(groups)?;
+                let mut seen = vec![0; self.members.len()];
+                slots.iter().flatten().for_each(|&index| seen[index] += 1);
+                if let Some(index) = seen.iter().position(|&count| count != 1) {
+                    return Err(HcSr04Error::InvalidConfig(format!(
+                        "sensor {:?} must be in exactly one group",
+                        self.members[index].name
+                    )))
+                }
+                slots
+            }
+        };
+
+        self.schedule = schedule;
+        self.slots = slots;
+        Ok(())
+    }
+
+    /// Runs the schedule once and returns every sensor's reading. `timeout` is
+    /// passed to each ping as for `dist_cm`.
+    pub fn frame(&mut self, timeout: Option<Duration>) -> Frame {
+        let started = Instant::now();
+        let mut results: Vec<Option<(Instant, Result<Distance, HcSr04Error>)>> =
+            self.members.iter().map(|_| None).collect();
+
+        for slot in &self.slots {
+            if let Some(next_fire) = self.next_fire {
+                sleep(next_fire.saturating_duration_since(Instant::now()));
+            }
+            let slot_start = Instant::now();
+
+            let mut fired = Vec::with_capacity(slot.len());
+            for &index in slot {
+                let at = Instant::now();
+                match self.members[index].sensor.fire(timeout) {
+                    Ok(ping) => fired.push((index, at, ping)),
+                    Err(err) => results[index] = Some((at, Err(err))),
+                }
+            }
+
+            for (index, at, ping) in fired {
+                let result = self.members[index].sensor.wait_echo(ping).map(|res| res.distance);
+                results[index] = Some((at, result));
+            }
+
+            let guard = slot.iter().map(|&index| self.members[index].guard).max().unwrap_or_default();
+            self.next_fire = Some(slot_start + guard);
+        }
+
+        let readings = self.members.iter().zip(results)
+            .filter_map(|(member, result)| result.map(|(at, result)| FrameReading {
+                name: member.name.clone(),
+                at,
+                result,
+            }))
+            .collect();
+
+        Frame {
+            started,
+            finished: Instant::now(),
+            readings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockBackend, MockEcho};
+
+    fn sensor(cm: &[f64]) -> HcSr04<MockBackend> {
+        let script = cm.iter().map(|&cm| MockEcho::from_distance(Distance::cm(cm)));
+        HcSr04::with_backend(MockBackend::with_script(script), Distance::cm(2.0))
+    }
+
+    fn array(names: &[&str]) -> SensorArray<MockBackend> {
+        let mut array = SensorArray::new();
+        for (i, name) in names.iter().enumerate() {
+            let cm = 10.0 * (i + 1) as f64;
+            array.add_with_guard(*name, sensor(&[cm, cm + 1.0]), Duration::from_millis(1)).unwrap();
+        }
+        array
+    }
+
+    fn groups(groups: &[&[&str]]) -> Schedule {
+        Schedule::Groups(groups.iter().map(|group| group.iter().map(|name| name.to_string()).collect()).collect())
+    }
+
+    fn cm(reading: &FrameReading) -> f64 {
+        let cm = reading.result.as_ref().unwrap().as_cm();
+        (cm * 10.0).round() / 10.0
+    }
+
+    #[test]
+    fn groups_validation() {
+        let mut array = array(&["left", "front", "right"]);
+        let mut err = |schedule| match array.set_schedule(schedule) {
+            Err(HcSr04Error::InvalidConfig(msg)) => msg,
+            other => panic!("expected the schedule to be rejected, got {:?}", other),
+        };
+
+        assert!(err(groups(&[&["left", "right"], &["front", "left"]])).contains("\"left\" must be in exactly one group"));
+        assert!(err(groups(&[&["left", "right"]])).contains("\"front\" must be in exactly one group"));
+        assert!(err(groups(&[&["left", "right"], &["front", "rear"]])).contains("unknown sensor \"rear\""));
+        assert!(err(Schedule::Custom(vec![vec!["rear".to_string()]])).contains("unknown sensor \"rear\""));
+        // a rejected schedule leaves the old one in place
+        assert_eq!(array.schedule(), &Schedule::RoundRobin);
+
+        array.set_schedule(groups(&[&["left", "right"], &["front"]])).unwrap();
+    }
+
+    #[test]
+    fn add_rolls_back_on_failure() {
+        let mut array = array(&["left", "right"]);
+        assert!(matches!(array.add("left", sensor(&[])), Err(HcSr04Error::InvalidConfig(_))));
+
+        array.set_schedule(groups(&[&["left", "right"]])).unwrap();
+        // a new sensor isn't in any group yet
+        assert!(matches!(array.add("front", sensor(&[])), Err(HcSr04Error::InvalidConfig(_))));
+        assert_eq!(array.names().collect::<Vec<_>>(), ["left", "right"]);
+        assert!(array.sensor_mut("front").is_none());
+
+        let frame = array.frame(None);
+        assert_eq!(frame.readings.len(), 2);
+    }
+
+    #[test]
+    fn frame_follows_schedule() {
+        let mut array = array(&["left", "front", "right"]);
+        let frame = array.frame(None);
+        let names: Vec<&str> = frame.readings.iter().map(|reading| reading.name.as_str()).collect();
+        assert_eq!(names, ["left", "front", "right"]);
+        assert_eq!(frame.readings.iter().map(cm).collect::<Vec<_>>(), [10.0, 20.0, 30.0]);
+        assert!(frame.readings.windows(2).all(|pair| pair[0].at < pair[1].at));
+        assert!(frame.started <= frame.readings[0].at && frame.readings[2].at <= frame.finished);
+
+        // readings stay in the order the sensors were added, whatever order they fire in
+        array.set_schedule(groups(&[&["right"], &["front"], &["left"]])).unwrap();
+        let frame = array.frame(None);
+        let names: Vec<&str> = frame.readings.iter().map(|reading| reading.name.as_str()).collect();
+        assert_eq!(names, ["left", "front", "right"]);
+        assert_eq!(frame.readings.iter().map(cm).collect::<Vec<_>>(), [11.0, 21.0, 31.0]);
+        assert!(frame.get("right").unwrap().at < frame.get("front").unwrap().at);
+        assert!(frame.get("front").unwrap().at < frame.get("left").unwrap().at);
+    }
+
+    #[test]
+    fn custom_schedule_repeats_and_skips() {
+        let mut array = array(&["left", "front", "right"]);
+        array.set_schedule(Schedule::Custom(vec![
+            vec!["left".to_string(), "right".to_string()],
+            vec!["left".to_string()],
+        ])).unwrap();
+
+        let frame = array.frame(None);
+        assert!(frame.get("front").is_none());
+        assert_eq!(frame.readings.len(), 2);
+        // the second ping of "left" wins
+        assert_eq!(cm(frame.get("left").unwrap()), 11.0);
+        assert_eq!(cm(frame.get("right").unwrap()), 30.0);
+        assert_eq!(array.sensor_mut("left").unwrap().backend().trigger_pulses(), 2);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{Distance, EchoBackend, EdgeEvent, HcSr04, HcSr04Error, LengthUnit, Measurement};
+use futures_util::stream::{self, Stream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// Borrowed event fd, owned by the backend.
+struct EventFd(RawFd);
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// `HcSr04` whose measurements await the echo on the tokio reactor instead of
+/// blocking in `poll`.
+///
+/// Only the trigger pulse itself (about 12 µs) still sleeps on the calling thread.
+pub struct AsyncHcSr04<B: EchoBackend> {
+    // declared first so it is deregistered before the backend closes the fd
+    fd: AsyncFd<EventFd>,
+    sensor: HcSr04<B>,
+}
+
+impl<B: EchoBackend> HcSr04<B> {
+    /// Must be called from within a tokio runtime. The backend needs an
+    /// [`EchoBackend::event_fd`], which rules out the
+    /// [`SinglePinBackend`](crate::SinglePinBackend) and the mocks.
+    pub fn into_async(self) -> Result<AsyncHcSr04<B>, HcSr04Error> {
+        let fd = match self.backend.event_fd() {
+            Some(fd) => AsyncFd::new(EventFd(fd)).map_err(HcSr04Error::Poll)?,
+            None => return Err(HcSr04Error::InvalidConfig("the backend has no event fd to await".to_string()))
+        };
+        Ok(AsyncHcSr04 { fd, sensor: self })
+    }
+}
+
+impl<B: EchoBackend> AsyncHcSr04<B> {
+    pub fn get_ref(&self) -> &HcSr04<B> {
+        &self.sensor
+    }
+
+    pub fn get_mut(&mut self) -> &mut HcSr04<B> {
+        &mut self.sensor
+    }
+
+    pub fn into_inner(self) -> HcSr04<B> {
+        self.sensor
+    }
+
+    async fn wait_edge(&mut self, deadline: Instant) -> Result<Option<EdgeEvent>, HcSr04Error> {
+        loop {
+            let mut guard = match time::timeout_at(deadline, self.fd.readable()).await {
+                Ok(guard) => guard.map_err(HcSr04Error::Poll)?,
+                Err(_) => return Ok(None)
+            };
+            match self.sensor.backend.wait_edge(Duration::ZERO)? {
+                Some(event) => return Ok(Some(event)),
+                None => guard.clear_ready()
+            }
+        }
+    }
+
+    async fn ping(&mut self, timeout: Option<Duration>) -> Result<Measurement, HcSr04Error> {
+        let ping = self.sensor.fire(timeout)?;
+        let deadline = Instant::from_std(ping.start_time) + ping.timeout;
+
+        let rising = self.wait_edge(deadline).await?;
+        let falling = match rising {
+            Some(_) => self.wait_edge(deadline).await?,
+            None => None
+        };
+
+        self.sensor.finish(ping, rising, falling)
+    }
+
+    /// Async [`HcSr04::measurement`].
+    pub async fn measurement(&mut self, timeout: Option<Duration>) -> Result<Measurement, HcSr04Error> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            match self.ping(timeout).await {
+                Ok(mut res) => {
+                    res.flags.retried = attempt > 0;
+                    return Ok(res)
+                }
+                Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. }) if attempt < self.sensor.settings.retries => {
+                    attempt += 1;
+                    time::sleep_until(started + crate::MIN_MEASUREMENT_CYCLE).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn dist(&mut self, timeout: Option<Duration>) -> Result<f64, HcSr04Error> {
+        self.measurement(timeout).await.map(|res| res.distance.as_cm())
+    }
+
+    /// Async [`HcSr04::dist_meter`].
+    pub async fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout).await?;
+        Ok(Distance::cm(res).to(LengthUnit::Meter))
+    }
+
+    /// Async [`HcSr04::dist_cm`].
+    pub async fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout).await?;
+        Ok(Distance::cm(res))
+    }
+
+    /// Async [`HcSr04::dist_mm`].
+    pub async fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout).await?;
+        Ok(Distance::cm(res).to(LengthUnit::Mm))
+    }
+
+    /// Endless stream of `dist_cm(None)` readings, one every `period` but no faster
+    /// than [`MIN_MEASUREMENT_CYCLE`](crate::MIN_MEASUREMENT_CYCLE).
+    pub fn readings(self, period: Duration) -> impl Stream<Item = Result<Distance, HcSr04Error>> {
+        let mut interval = time::interval(period.max(crate::MIN_MEASUREMENT_CYCLE));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        stream::unfold((self, interval), |(mut sensor, mut interval)| async move {
+            interval.tick().await;
+            let reading = sensor.dist_cm(None).await;
+            Some((reading, (sensor, interval)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Edge, MockBackend, MockEcho, TimingSource};
+    use futures_util::StreamExt;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    /// Echo edges delivered through a pipe, so the reactor has a real fd to
+    /// wait on: each edge is queued and a byte written, from a thread that
+    /// sleeps for the pulse width in between.
+    struct PipeBackend {
+        read: RawFd,
+        write: RawFd,
+        script: VecDeque<MockEcho>,
+        edges: Arc<Mutex<VecDeque<EdgeEvent>>>,
+        echo: Option<JoinHandle<()>>,
+        trigger: bool,
+        /// fake kernel clock, in ns
+        clock: u64,
+    }
+
+    impl PipeBackend {
+        fn new<I: IntoIterator<Item = MockEcho>>(script: I) -> Self {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) }, 0);
+            Self {
+                read: fds[0],
+                write: fds[1],
+                script: script.into_iter().collect(),
+                edges: Arc::default(),
+                echo: None,
+                trigger: false,
+                clock: 0,
+            }
+        }
+
+        fn finish_echo(&mut self) {
+            if let Some(echo) = self.echo.take() {
+                echo.join().unwrap();
+            }
+        }
+    }
+
+    impl Drop for PipeBackend {
+        fn drop(&mut self) {
+            self.finish_echo();
+            unsafe {
+                libc::close(self.read);
+                libc::close(self.write);
+            }
+        }
+    }
+
+    impl EchoBackend for PipeBackend {
+        fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+            if self.trigger && !high {
+                self.finish_echo();
+                self.clock += 100_000_000;
+                let (clock, write, edges) = (self.clock, self.write, self.edges.clone());
+                let edge = move |edge, offset: Duration| {
+                    let event = EdgeEvent { edge, at: std::time::Instant::now(), timestamp: Some(clock + offset.as_nanos() as u64) };
+                    edges.lock().unwrap().push_back(event);
+                    assert_eq!(unsafe { libc::write(write, [0u8].as_ptr().cast(), 1) }, 1);
+                };
+                let echo = self.script.pop_front().unwrap_or(MockEcho::NoEcho);
+                self.echo = Some(thread::spawn(move || match echo {
+                    MockEcho::Pulse(width) => {
+                        edge(Edge::Rising, Duration::ZERO);
+                        thread::sleep(width);
+                        edge(Edge::Falling, width);
+                    }
+                    MockEcho::NoFall => edge(Edge::Rising, Duration::ZERO),
+                    MockEcho::NoEcho => (),
+                }));
+            }
+            self.trigger = high;
+            Ok(())
+        }
+
+        fn arm_echo(&mut self) -> Result<(), HcSr04Error> {
+            self.finish_echo();
+            let mut buf = [0u8; 16];
+            while unsafe { libc::read(self.read, buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+            self.edges.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn wait_edge(&mut self, _timeout: Duration) -> Result<Option<EdgeEvent>, HcSr04Error> {
+            // only called once the pipe is readable, or to check it
+            let mut byte = 0u8;
+            match unsafe { libc::read(self.read, (&mut byte as *mut u8).cast(), 1) } {
+                1 => Ok(self.edges.lock().unwrap().pop_front()),
+                _ => Ok(None),
+            }
+        }
+
+        fn event_fd(&self) -> Option<RawFd> {
+            Some(self.read)
+        }
+    }
+
+    fn sensor<I: IntoIterator<Item = MockEcho>>(script: I) -> AsyncHcSr04<PipeBackend> {
+        HcSr04::with_backend(PipeBackend::new(script), Distance::cm(2.0)).into_async().unwrap()
+    }
+
+    fn assert_cm(distance: Distance, cm: f64) {
+        assert!((distance.as_cm() - cm).abs() < 0.01, "{:?}", distance);
+    }
+
+    #[test]
+    fn rejects_backends_without_event_fd() {
+        let hcsr04 = HcSr04::with_backend(MockBackend::new(), Distance::cm(2.0));
+        assert!(matches!(hcsr04.into_async(), Err(HcSr04Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn measures_on_the_reactor() {
+        let mut hcsr04 = sensor([
+            MockEcho::from_distance(Distance::cm(50.0)),
+            MockEcho::NoEcho,
+            MockEcho::NoFall,
+            MockEcho::from_distance(Distance::cm(120.0)),
+        ]);
+
+        let res = hcsr04.measurement(None).await.unwrap();
+        assert_cm(res.distance, 50.0);
+        assert_eq!(res.timing, TimingSource::Kernel);
+        assert!(matches!(hcsr04.measurement(None).await, Err(HcSr04Error::NoEchoStart { .. })));
+        assert!(matches!(hcsr04.measurement(None).await, Err(HcSr04Error::EchoNeverEnded { .. })));
+        assert_cm(hcsr04.dist_cm(Some(Duration::from_millis(50))).await.unwrap(), 120.0);
+    }
+
+    #[tokio::test]
+    async fn streams_readings() {
+        let distances = [30.0, 45.5, 80.0];
+        let hcsr04 = sensor(distances.map(|cm| MockEcho::from_distance(Distance::cm(cm))));
+
+        let readings: Vec<_> = hcsr04.readings(Duration::ZERO).take(4).collect().await;
+        for (reading, cm) in readings.iter().zip(distances) {
+            assert_cm(*reading.as_ref().unwrap(), cm);
+        }
+        // the script has run out
+        assert!(matches!(readings[3], Err(HcSr04Error::NoEchoStart { .. })));
+    }
+}
@@ -0,0 +1,614 @@
+use crate::{Distance, HcSr04Error, SPEED_OF_SOUND};
+use gpio_cdev::{EventRequestFlags, EventType, Line, LineEventHandle, LineHandle, LineRequestFlags};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// An edge seen on the echo line.
+#[derive(Debug, Clone, Copy)]
+pub struct EdgeEvent {
+    pub edge: Edge,
+    /// when userspace saw the edge
+    pub at: Instant,
+    /// kernel timestamp of the edge in ns, if the backend has one
+    pub timestamp: Option<u64>,
+}
+
+/// What `HcSr04` needs from the GPIO side: drive the trigger, arm edge
+/// detection on the echo line and wait for the next edge.
+pub trait EchoBackend {
+    /// Drives the trigger line high (`true`) or low (`false`).
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error>;
+
+    /// Gets the echo line ready for the next ping, dropping edges left over from
+    /// earlier ones. Called before every trigger pulse.
+    fn arm_echo(&mut self) -> Result<(), HcSr04Error>;
+
+    /// Waits up to `timeout` for the next echo edge. Returns `Ok(None)` on timeout.
+    fn wait_edge(&mut self, timeout: Duration) -> Result<Option<EdgeEvent>, HcSr04Error>;
+
+    /// File descriptor that turns readable when an echo edge is queued, for
+    /// event loops. Backends without one must not block in `wait_edge`.
+    fn event_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Like [`EchoBackend`], for one trigger line shared by several echo lines.
+pub trait MultiEchoBackend {
+    fn echo_count(&self) -> usize;
+
+    /// Drives the shared trigger line high (`true`) or low (`false`).
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error>;
+
+    /// [`EchoBackend::arm_echo`] for every echo line.
+    fn arm_echoes(&mut self) -> Result<(), HcSr04Error>;
+
+    /// Waits up to `timeout` for the next edge on any echo line and says which
+    /// line it was on. Returns `Ok(None)` on timeout.
+    fn wait_any_edge(&mut self, timeout: Duration) -> Result<Option<(usize, EdgeEvent)>, HcSr04Error>;
+}
+
+/// Polls `pollfds` at once, returns how many have events.
+fn poll_many(pollfds: &mut [libc::pollfd], timeout: Duration) -> Result<usize, HcSr04Error> {
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+    unsafe {
+        match libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) {
+            -1 => Err(HcSr04Error::Poll(io::Error::last_os_error())),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+fn pollfd(fd: RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN | libc::POLLPRI,
+        revents: 0,
+    }
+}
+
+pub(crate) fn poll_with_timeout(fd: i32, timeout: Duration) -> Result<bool, HcSr04Error> {
+    // 0 on timeout, 1 with an event available
+    Ok(poll_many(&mut [pollfd(fd)], timeout)? > 0)
+}
+
+fn request_echo(echo: &Line, consumer: &str, bias: Bias) -> Result<LineEventHandle, HcSr04Error> {
+    let events_req = echo.events(
+        bias.input_flags(),
+        EventRequestFlags::BOTH_EDGES,
+        consumer);
+
+    events_req.map_err(|source| HcSr04Error::line_request(echo.offset(), source))
+}
+
+/// Reads one already queued event.
+fn read_edge(events: &mut LineEventHandle) -> Result<EdgeEvent, HcSr04Error> {
+    match events.next() {
+        Some(Ok(event)) => {
+            let edge = match event.event_type() {
+                EventType::RisingEdge => Edge::Rising,
+                EventType::FallingEdge => Edge::Falling,
+            };
+            Ok(EdgeEvent { edge, at: Instant::now(), timestamp: Some(event.timestamp()) })
+        }
+        Some(Err(err)) => Err(err.into()),
+        None => Err(HcSr04Error::Gpio(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+    }
+}
+
+/// Drops whatever edges are queued on `events`.
+fn drain(events: &mut LineEventHandle) -> Result<(), HcSr04Error> {
+    // edges from a late echo of the previous ping are still queued in the kernel
+    while poll_with_timeout(events.as_raw_fd(), Duration::ZERO)? {
+        if events.next().is_none() {
+            break
+        }
+    }
+    Ok(())
+}
+
+/// Pull resistor on an input line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Bias {
+    /// leave it however the line is currently set up
+    #[default]
+    AsIs,
+    PullUp,
+    PullDown,
+    /// neither pull-up nor pull-down
+    Disabled,
+}
+impl Bias {
+    /// Request flags for an input line with this bias. The v1 uAPI takes the
+    /// `GPIOHANDLE_REQUEST_BIAS_*` bits since Linux 5.5, gpio-cdev just doesn't name them.
+    pub(crate) fn input_flags(self) -> LineRequestFlags {
+        let bias = match self {
+            Bias::AsIs => 0,
+            Bias::PullUp => 1 << 5,
+            Bias::PullDown => 1 << 6,
+            Bias::Disabled => 1 << 7,
+        };
+        LineRequestFlags::INPUT | LineRequestFlags::from_bits_retain(bias)
+    }
+}
+
+/// Backend talking to real hardware through the GPIO character device.
+///
+/// The echo line is requested for edge events once, up front, and kept for the
+/// lifetime of the backend so no edge can slip through between trigger and request.
+pub struct CdevBackend {
+    trig: LineHandle,
+    events: LineEventHandle,
+}
+
+impl CdevBackend {
+    /// `trig` must already be requested as an output.
+    pub fn new(trig: LineHandle, echo: Line) -> Result<Self, HcSr04Error> {
+        Self::with_echo_options(trig, echo, "hc-sr04-echo", Bias::AsIs)
+    }
+
+    /// Like [`CdevBackend::new`] with a custom consumer label and bias for the echo line.
+    pub fn with_echo_options(trig: LineHandle, echo: Line, consumer: &str, bias: Bias) -> Result<Self, HcSr04Error> {
+        Ok(Self {
+            trig,
+            events: request_echo(&echo, consumer, bias)?,
+        })
+    }
+}
+
+impl EchoBackend for CdevBackend {
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+        Ok(self.trig.set_value(high as u8)?)
+    }
+
+    fn arm_echo(&mut self) -> Result<(), HcSr04Error> {
+        drain(&mut self.events)
+    }
+
+    fn wait_edge(&mut self, timeout: Duration) -> Result<Option<EdgeEvent>, HcSr04Error> {
+        if !poll_with_timeout(self.events.as_raw_fd(), timeout)? {
+            return Ok(None)
+        }
+        read_edge(&mut self.events).map(Some)
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        Some(self.events.as_raw_fd())
+    }
+}
+
+/// [`MultiEchoBackend`] on the GPIO character device: one trigger handle and
+/// an event handle per echo line, all polled together.
+pub struct CdevMultiBackend {
+    trig: LineHandle,
+    echoes: Vec<LineEventHandle>,
+}
+
+impl CdevMultiBackend {
+    /// `trig` must already be requested as an output.
+    pub fn new(trig: LineHandle, echoes: &[Line]) -> Result<Self, HcSr04Error> {
+        Self::with_echo_options(trig, echoes, "hc-sr04-echo", Bias::AsIs)
+    }
+
+    pub fn with_echo_options(trig: LineHandle, echoes: &[Line], consumer: &str, bias: Bias) -> Result<Self, HcSr04Error> {
+        let echoes = echoes.iter()
+            .map(|echo| request_echo(echo, consumer, bias))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { trig, echoes })
+    }
+}
+
+impl MultiEchoBackend for CdevMultiBackend {
+    fn echo_count(&self) -> usize {
+        self.echoes.len()
+    }
+
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+        Ok(self.trig.set_value(high as u8)?)
+    }
+
+    fn arm_echoes(&mut self) -> Result<(), HcSr04Error> {
+        self.echoes.iter_mut().try_for_each(drain)
+    }
+
+    fn wait_any_edge(&mut self, timeout: Duration) -> Result<Option<(usize, EdgeEvent)>, HcSr04Error> {
+        let mut pollfds: Vec<_> = self.echoes.iter().map(|events| pollfd(events.as_raw_fd())).collect();
+        if poll_many(&mut pollfds, timeout)? == 0 {
+            return Ok(None)
+        }
+
+        match pollfds.iter().position(|pollfd| pollfd.revents != 0) {
+            Some(index) => Ok(Some((index, read_edge(&mut self.echoes[index])?))),
+            None => Ok(None)
+        }
+    }
+}
+
+enum PinMode {
+    Output(LineHandle),
+    Input(LineEventHandle),
+    /// between directions, or after a failed re-request
+    Released,
+}
+
+/// Backend for sensors with one signal pin for trigger and echo, such as the
+/// Parallax PING))) and Grove ultrasonic ranger.
+///
+/// The line is driven as an output for the trigger pulse and re-requested as an
+/// input with edge events as soon as the pulse ends. That takes a couple of
+/// ioctls per ping, well within the ~200 µs the sensor needs to start its echo.
+///
+/// With a new event handle every ping there is no lasting fd to hand to an event
+/// loop, so this backend has no [`EchoBackend::event_fd`] and can't be used
+/// with `into_async`.
+pub struct SinglePinBackend {
+    line: Line,
+    mode: PinMode,
+    /// level last driven on the line
+    high: bool,
+    consumer: String,
+    bias: Bias,
+}
+
+impl SinglePinBackend {
+    /// Claims `line` as an output straight away, so a busy line fails here.
+    pub fn new(line: Line) -> Result<Self, HcSr04Error> {
+        Self::with_options(line, "hc-sr04", Bias::AsIs)
+    }
+
+    /// `bias` applies while listening for the echo.
+    pub fn with_options(line: Line, consumer: &str, bias: Bias) -> Result<Self, HcSr04Error> {
+        let handle = Self::request_output(&line, consumer)?;
+        Ok(Self {
+            line,
+            mode: PinMode::Output(handle),
+            high: false,
+            consumer: consumer.to_string(),
+            bias,
+        })
+    }
+
+    fn request_output(line: &Line, consumer: &str) -> Result<LineHandle, HcSr04Error> {
+        line.request(LineRequestFlags::OUTPUT, 0, consumer)
+            .map_err(|source| HcSr04Error::line_request(line.offset(), source))
+    }
+
+    fn output(&mut self) -> Result<&LineHandle, HcSr04Error> {
+        if !matches!(self.mode, PinMode::Output(_)) {
+            // the kernel won't hand the line out again while the event handle is open
+            self.mode = PinMode::Released;
+            self.mode = PinMode::Output(Self::request_output(&self.line, &self.consumer)?);
+        }
+        match &self.mode {
+            PinMode::Output(handle) => Ok(handle),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl EchoBackend for SinglePinBackend {
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+        let was_high = self.high;
+        self.output()?.set_value(high as u8)?;
+        self.high = high;
+
+        if was_high && !high {
+            // end of the trigger pulse, switch over to listening once the
+            // output handle has let go of the line
+            self.mode = PinMode::Released;
+            self.mode = PinMode::Input(request_echo(&self.line, &self.consumer, self.bias)?);
+        }
+        Ok(())
+    }
+
+    fn arm_echo(&mut self) -> Result<(), HcSr04Error> {
+        self.output()?;
+        Ok(())
+    }
+
+    fn wait_edge(&mut self, timeout: Duration) -> Result<Option<EdgeEvent>, HcSr04Error> {
+        let events = match &mut self.mode {
+            PinMode::Input(events) => events,
+            PinMode::Output(_) | PinMode::Released => return Ok(None)
+        };
+        if !poll_with_timeout(events.as_raw_fd(), timeout)? {
+            return Ok(None)
+        }
+        read_edge(events).map(Some)
+    }
+}
+
+/// One scripted response of [`MockBackend`] to a trigger pulse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MockEcho {
+    /// echo goes high and stays high for the given time
+    Pulse(Duration),
+    /// echo never goes high
+    NoEcho,
+    /// echo goes high and never comes back down
+    NoFall,
+}
+impl MockEcho {
+    /// Pulse that the sensor would produce for an object at `dist`.
+    pub fn from_distance(dist: Distance) -> Self {
+        MockEcho::Pulse(Duration::from_secs_f64(2.0 * dist.as_meters() / SPEED_OF_SOUND.as_meters_per_sec()))
+    }
+}
+
+/// In-memory backend replaying scripted echoes, one per trigger pulse.
+/// Runs out to [`MockEcho::NoEcho`] once the script is empty.
+///
+/// Time is virtual: edges sit at their scripted offset from the end of the
+/// trigger pulse, and `wait_edge` advances the clock by its timeout when
+/// nothing is due within it. An echo longer than the timeout therefore times
+/// out, without any real waiting.
+#[derive(Debug)]
+pub struct MockBackend {
+    script: VecDeque<MockEcho>,
+    /// edges of the current ping, with their offset from the trigger pulse
+    pending: VecDeque<(Duration, EdgeEvent)>,
+    /// virtual time since the trigger pulse ended
+    elapsed: Duration,
+    trigger: bool,
+    pulses: usize,
+    /// fake kernel clock, in ns
+    clock: u64,
+    kernel_timestamps: bool,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self {
+            script: VecDeque::new(),
+            pending: VecDeque::new(),
+            elapsed: Duration::ZERO,
+            trigger: false,
+            pulses: 0,
+            clock: 0,
+            kernel_timestamps: true,
+        }
+    }
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_script<I: IntoIterator<Item = MockEcho>>(script: I) -> Self {
+        Self {
+            script: script.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether edges carry kernel timestamps. Turning them off exercises the userspace fallback.
+    pub fn set_kernel_timestamps(&mut self, enabled: bool) {
+        self.kernel_timestamps = enabled;
+    }
+
+    /// Queues the response to a future trigger pulse.
+    pub fn push(&mut self, echo: MockEcho) {
+        self.script.push_back(echo);
+    }
+
+    /// Number of trigger pulses emitted so far.
+    pub fn trigger_pulses(&self) -> usize {
+        self.pulses
+    }
+
+    /// Number of scripted echoes not consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.script.len()
+    }
+}
+
+impl EchoBackend for MockBackend {
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+        // the sensor fires on the falling edge of the trigger pulse
+        if self.trigger && !high {
+            self.pulses += 1;
+
+            // leave a gap between pings so timestamps never repeat
+            self.clock += 100_000_000;
+            self.elapsed = Duration::ZERO;
+            let base = Instant::now();
+            let ts = |offset: u64| self.kernel_timestamps.then_some(self.clock + offset);
+            match self.script.pop_front().unwrap_or(MockEcho::NoEcho) {
+                MockEcho::Pulse(width) => {
+                    let rising = EdgeEvent { edge: Edge::Rising, at: base, timestamp: ts(0) };
+                    let falling = EdgeEvent { edge: Edge::Falling, at: base + width, timestamp: ts(width.as_nanos() as u64) };
+                    self.pending.push_back((Duration::ZERO, rising));
+                    self.pending.push_back((width, falling));
+                }
+                MockEcho::NoFall => {
+                    let rising = EdgeEvent { edge: Edge::Rising, at: base, timestamp: ts(0) };
+                    self.pending.push_back((Duration::ZERO, rising));
+                }
+                MockEcho::NoEcho => (),
+            }
+        }
+        self.trigger = high;
+        Ok(())
+    }
+
+    fn arm_echo(&mut self) -> Result<(), HcSr04Error> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn wait_edge(&mut self, timeout: Duration) -> Result<Option<EdgeEvent>, HcSr04Error> {
+        match self.pending.front() {
+            Some((offset, _)) if *offset <= self.elapsed + timeout => {
+                self.elapsed = self.elapsed.max(*offset);
+                Ok(self.pending.pop_front().map(|(_, event)| event))
+            }
+            _ => {
+                self.elapsed += timeout;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// [`MultiEchoBackend`] made of one [`MockBackend`] per echo line, all fired
+/// by the same trigger.
+#[derive(Debug, Default)]
+pub struct MockMultiBackend {
+    echoes: Vec<MockBackend>,
+}
+
+impl MockMultiBackend {
+    pub fn new(echoes: Vec<MockBackend>) -> Self {
+        Self { echoes }
+    }
+
+    pub fn echo(&self, index: usize) -> Option<&MockBackend> {
+        self.echoes.get(index)
+    }
+
+    pub fn echo_mut(&mut self, index: usize) -> Option<&mut MockBackend> {
+        self.echoes.get_mut(index)
+    }
+}
+
+impl MultiEchoBackend for MockMultiBackend {
+    fn echo_count(&self) -> usize {
+        self.echoes.len()
+    }
+
+    fn set_trigger(&mut self, high: bool) -> Result<(), HcSr04Error> {
+        self.echoes.iter_mut().try_for_each(|echo| echo.set_trigger(high))
+    }
+
+    fn arm_echoes(&mut self) -> Result<(), HcSr04Error> {
+        self.echoes.iter_mut().try_for_each(|echo| echo.arm_echo())
+    }
+
+    fn wait_any_edge(&mut self, timeout: Duration) -> Result<Option<(usize, EdgeEvent)>, HcSr04Error> {
+        // hand out edges in the order they happened across all lines, on one
+        // virtual clock shared by all of them
+        let now = self.echoes.iter().map(|echo| echo.elapsed).max().unwrap_or_default();
+        let next = self.echoes.iter().enumerate()
+            .filter_map(|(index, echo)| echo.pending.front().map(|(offset, _)| (index, *offset)))
+            .min_by_key(|(_, offset)| *offset);
+
+        match next {
+            Some((index, offset)) if offset <= now + timeout => {
+                self.echoes.iter_mut().for_each(|echo| echo.elapsed = now.max(offset));
+                Ok(self.echoes[index].pending.pop_front().map(|(_, event)| (index, event)))
+            }
+            _ => {
+                self.echoes.iter_mut().for_each(|echo| echo.elapsed = now + timeout);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HcSr04, TimingSource};
+
+    fn sensor<I: IntoIterator<Item = MockEcho>>(script: I) -> HcSr04<MockBackend> {
+        HcSr04::with_backend(MockBackend::with_script(script), Distance::cm(2.0))
+    }
+
+    #[test]
+    fn valid_reading() {
+        let mut hcsr04 = sensor([MockEcho::from_distance(Distance::cm(50.0))]);
+        let res = hcsr04.measurement(None).unwrap();
+        assert!((res.distance.as_cm() - 50.0).abs() < 0.01, "{:?}", res.distance);
+        assert_eq!(res.timing, TimingSource::Kernel);
+        assert_eq!(hcsr04.last_timing_source(), Some(TimingSource::Kernel));
+        assert_eq!(hcsr04.backend().trigger_pulses(), 1);
+        assert_eq!(hcsr04.backend().remaining(), 0);
+    }
+
+    #[test]
+    fn no_echo() {
+        let mut hcsr04 = sensor([MockEcho::NoEcho]);
+        assert!(matches!(hcsr04.measurement(None), Err(HcSr04Error::NoEchoStart { .. })));
+        // an empty script behaves the same
+        assert!(matches!(hcsr04.measurement(None), Err(HcSr04Error::NoEchoStart { .. })));
+        assert_eq!(hcsr04.backend().trigger_pulses(), 2);
+    }
+
+    #[test]
+    fn echo_stuck_high() {
+        let mut hcsr04 = sensor([MockEcho::NoFall]);
+        assert!(matches!(hcsr04.measurement(None), Err(HcSr04Error::EchoNeverEnded { .. })));
+    }
+
+    #[test]
+    fn echo_longer_than_timeout() {
+        let mut hcsr04 = sensor([MockEcho::Pulse(Duration::from_millis(50)), MockEcho::Pulse(Duration::from_millis(50))]);
+        assert!(matches!(hcsr04.measurement(None), Err(HcSr04Error::EchoNeverEnded { .. })));
+        // the timeout covers the way there and back
+        let res = hcsr04.measurement(Some(Duration::from_millis(30))).unwrap();
+        assert_eq!(res.echo_high, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn userspace_fallback() {
+        let mut backend = MockBackend::with_script([MockEcho::from_distance(Distance::cm(120.0))]);
+        backend.set_kernel_timestamps(false);
+        let mut hcsr04 = HcSr04::with_backend(backend, Distance::cm(2.0));
+        let res = hcsr04.measurement(None).unwrap();
+        assert_eq!(res.timing, TimingSource::Userspace);
+        assert_eq!(hcsr04.last_timing_source(), Some(TimingSource::Userspace));
+        assert!((res.distance.as_cm() - 120.0).abs() < 0.01, "{:?}", res.distance);
+    }
+
+    #[test]
+    fn range_rejection() {
+        let mut hcsr04 = sensor([
+            MockEcho::from_distance(Distance::cm(1.0)),
+            MockEcho::from_distance(Distance::cm(80.0)),
+            MockEcho::from_distance(Distance::cm(30.0)),
+        ]);
+        hcsr04.set_max_range(Some(Distance::cm(50.0))).unwrap();
+
+        match hcsr04.measurement(None) {
+            Err(HcSr04Error::OutOfRange { distance, min, .. }) => assert!(distance < min),
+            other => panic!("expected a reading below the minimum, got {:?}", other),
+        }
+        match hcsr04.measurement(None) {
+            Err(HcSr04Error::OutOfRange { distance, max: Some(max), .. }) => assert!(distance > max),
+            other => panic!("expected a reading beyond the maximum, got {:?}", other),
+        }
+        assert!(hcsr04.measurement(None).is_ok());
+    }
+
+    #[test]
+    fn multi_backend_orders_edges() {
+        let mut backend = MockMultiBackend::new(vec![
+            MockBackend::with_script([MockEcho::Pulse(Duration::from_millis(3))]),
+            MockBackend::with_script([MockEcho::Pulse(Duration::from_millis(1))]),
+        ]);
+        backend.arm_echoes().unwrap();
+        backend.set_trigger(true).unwrap();
+        backend.set_trigger(false).unwrap();
+
+        let timeout = Duration::from_millis(1);
+        let mut edges = Vec::new();
+        while let Some((index, event)) = backend.wait_any_edge(timeout).unwrap() {
+            edges.push((index, event.edge));
+        }
+        // the long echo's falling edge is past the first timeout
+        assert_eq!(edges, [(0, Edge::Rising), (1, Edge::Rising), (1, Edge::Falling)]);
+        assert_eq!(backend.wait_any_edge(timeout).unwrap().map(|(index, event)| (index, event.edge)), Some((0, Edge::Falling)));
+    }
+}
@@ -0,0 +1,352 @@
+use hcsr04_gpio_cdev::*;
+use std::error::Error;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::*;
+
+const USAGE: &str = "\
+usage: hcsr04 <command> [options]
+
+commands:
+  read          take one reading, or several with --count
+  stats         ping repeatedly and summarise timeouts and spread
+  probe         check that the echo line answers the trigger
+  list-chips    list the GPIO chips and their labels
+  daemon        own the sensor and serve its readings on a Unix socket
+
+sensor options (read, stats, probe, daemon):
+  --trig N              trigger line offset, required
+  --echo N              echo line offset, required
+  --chip PATH           GPIO chip, e.g. /dev/gpiochip0; default is the header chip
+  --chip-label LABEL    GPIO chip by label, e.g. pinctrl-rp1
+  --min DIST            minimum range, e.g. 2cm (default 2cm)
+  --max DIST            maximum range, e.g. 4m (default none)
+  --temp CELSIUS        air temperature for the speed of sound (default 343 m/s)
+
+read, stats and daemon options:
+  -n, --count N         number of pings, 0 to keep going (default 1 for read, 100 for stats)
+  --rate HZ             pings per second, capped by the 60 ms cycle (default 10)
+  --sensor NAME         sensor id in json, csv and influx output and on the daemon socket (default hcsr04)
+
+read options:
+  --unit mm|cm|m        unit to print readings in (default cm)
+  --format FORMAT       text, json (JSON Lines), csv or influx (line protocol); default text
+
+probe options:
+  -n, --count N         number of pings (default 5)
+
+daemon options:
+  --socket PATH         socket to serve on (default /run/hcsr04.sock)
+";
+
+struct Args {
+    command: String,
+    trig: Option<u32>,
+    echo: Option<u32>,
+    chip: Option<String>,
+    chip_label: Option<String>,
+    min: Distance,
+    max: Option<Distance>,
+    temp: Option<f64>,
+    count: Option<u64>,
+    /// time between pings for `--rate`, never below the sensor's cycle
+    period: Duration,
+    unit: LengthUnit,
+    /// `None` for plain text
+    format: Option<OutputFormat>,
+    sensor: String,
+    socket: String,
+}
+
+fn parse_value<T>(name: &str, value: Option<String>) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = match value {
+        Some(value) => value,
+        None => return Err(format!("{} needs a value", name))
+    };
+    value.parse().map_err(|err| format!("invalid value {:?} for {}: {}", value, name, err))
+}
+
+fn parse_unit(value: &str) -> Result<LengthUnit, String> {
+    match value {
+        "mm" => Ok(LengthUnit::Mm),
+        "cm" => Ok(LengthUnit::Cm),
+        "m" => Ok(LengthUnit::Meter),
+        _ => Err(format!("invalid unit {:?}, expected mm, cm or m", value)),
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Err("missing command".to_string())
+    };
+
+    let mut parsed = Args {
+        command,
+        trig: None,
+        echo: None,
+        chip: None,
+        chip_label: None,
+        min: Distance::cm(2.0),
+        max: None,
+        temp: None,
+        count: None,
+        period: Duration::from_millis(100),
+        unit: LengthUnit::Cm,
+        format: None,
+        sensor: "hcsr04".to_string(),
+        socket: "/run/hcsr04.sock".to_string(),
+    };
+
+    let mut rate: f64 = 10.0;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trig" => parsed.trig = Some(parse_value(&arg, args.next())?),
+            "--echo" => parsed.echo = Some(parse_value(&arg, args.next())?),
+            "--chip" => parsed.chip = Some(parse_value(&arg, args.next())?),
+            "--chip-label" => parsed.chip_label = Some(parse_value(&arg, args.next())?),
+            "--min" => parsed.min = parse_value(&arg, args.next())?,
+            "--max" => parsed.max = Some(parse_value(&arg, args.next())?),
+            "--temp" => parsed.temp = Some(parse_value(&arg, args.next())?),
+            "-n" | "--count" => parsed.count = Some(parse_value(&arg, args.next())?),
+            "--rate" => rate = parse_value(&arg, args.next())?,
+            "--unit" => parsed.unit = parse_unit(&parse_value::<String>(&arg, args.next())?)?,
+            "--format" => match parse_value::<String>(&arg, args.next())?.as_str() {
+                "text" => parsed.format = None,
+                format => parsed.format = Some(format.parse()?),
+            },
+            "--sensor" => parsed.sensor = parse_value(&arg, args.next())?,
+            "--socket" => parsed.socket = parse_value(&arg, args.next())?,
+            _ => return Err(format!("unknown option {:?}", arg)),
+        }
+    }
+
+    if rate.is_nan() || rate <= 0.0 {
+        return Err("--rate must be positive".to_string())
+    }
+    // a tiny rate gives a period that a Duration, or an Instant pacing by it, can't hold
+    parsed.period = match Duration::try_from_secs_f64(1.0 / rate) {
+        Ok(period) if Instant::now().checked_add(period).is_some() => period.max(MIN_MEASUREMENT_CYCLE),
+        _ => return Err(format!("--rate {:e} is too low", rate))
+    };
+    Ok(parsed)
+}
+
+fn open_sensor(args: &Args) -> Result<HcSr04, Box<dyn Error>> {
+    let (trig, echo) = match (args.trig, args.echo) {
+        (Some(trig), Some(echo)) => (trig, echo),
+        _ => return Err("--trig and --echo are required".into())
+    };
+
+    let mut builder = HcSr04::builder().pins(trig, echo).min_range(args.min);
+    if let Some(path) = &args.chip {
+        builder = builder.chip_path(path);
+    } else if let Some(label) = &args.chip_label {
+        builder = builder.chip_label(label);
+    }
+    if let Some(max) = args.max {
+        builder = builder.max_range(max);
+    }
+    if let Some(temp) = args.temp {
+        builder = builder.speed_of_sound(Environment::new(temp));
+    }
+    Ok(builder.build()?)
+}
+
+/// Runs `ping` `count` times (forever for 0), one every `period`.
+fn paced<F>(count: u64, period: Duration, mut ping: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut() -> Result<(), Box<dyn Error>>,
+{
+    let mut next = Instant::now();
+    let mut done = 0;
+    while count == 0 || done < count {
+        sleep(next.saturating_duration_since(Instant::now()));
+        next += period;
+        ping()?;
+        done += 1;
+    }
+    Ok(())
+}
+
+fn print_reading(unit: LengthUnit, reading: &Reading) {
+    match reading {
+        Reading::Valid(distance) => println!("{:.2}", distance.to(unit)),
+        Reading::TooNear(distance) => println!("too near ({:.2})", distance.to(unit)),
+        Reading::OutOfRange(Some(distance)) => println!("out of range ({:.2})", distance.to(unit)),
+        Reading::OutOfRange(None) => println!("no echo"),
+        Reading::Fault(err) => println!("error: {}", err),
+    }
+}
+
+fn read(args: &Args) -> Result<ExitCode, Box<dyn Error>> {
+    let mut sensor = open_sensor(args)?;
+    let count = args.count.unwrap_or(1);
+
+    let mut writer = args
+        .format
+        .map(|format| ReadingWriter::new(std::io::stdout().lock(), format).with_unit(args.unit));
+
+    let mut failed = false;
+    paced(count, args.period, || {
+        let wall_clock = SystemTime::now();
+        let reading = sensor.reading(None);
+        failed = reading.is_fault();
+        match &mut writer {
+            Some(writer) => writer.write(&args.sensor, wall_clock, &reading)?,
+            None => print_reading(args.unit, &reading),
+        }
+        Ok(())
+    })?;
+
+    // a single reading doubles as a health check for scripts
+    Ok(if count == 1 && failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+fn stats(args: &Args) -> Result<ExitCode, Box<dyn Error>> {
+    let mut sensor = open_sensor(args)?;
+    let count = args.count.unwrap_or(100);
+    if count == 0 {
+        return Err("stats needs a finite --count".into())
+    }
+
+    let mut readings = Vec::with_capacity(count as usize);
+    let mut timeouts = 0;
+    let mut out_of_range = 0;
+    let mut kernel_timed = 0;
+    paced(count, args.period, || {
+        match sensor.measurement(None) {
+            Ok(res) => {
+                if res.timing == TimingSource::Kernel {
+                    kernel_timed += 1;
+                }
+                readings.push(res.distance.value_in(args.unit));
+            }
+            Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. }) => timeouts += 1,
+            Err(HcSr04Error::OutOfRange { .. }) => out_of_range += 1,
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    })?;
+
+    let suffix = args.unit.suffix();
+    println!("pings:         {}", count);
+    println!("valid:         {}", readings.len());
+    println!("timeouts:      {} ({:.1}%)", timeouts, 100.0 * timeouts as f64 / count as f64);
+    println!("out of range:  {}", out_of_range);
+    if readings.is_empty() {
+        return Ok(ExitCode::FAILURE)
+    }
+
+    let n = readings.len() as f64;
+    let mean = readings.iter().sum::<f64>() / n;
+    let std_dev = (readings.iter().map(|val| (val - mean).powi(2)).sum::<f64>() / n).sqrt();
+    let min = readings.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = readings.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    println!("min/mean/max:  {:.2} / {:.2} / {:.2} {}", min, mean, max, suffix);
+    println!("median:        {:.2} {}", median(&readings).unwrap_or(mean), suffix);
+    println!("jitter (std):  {:.2} {}", std_dev, suffix);
+    println!("kernel timed:  {} of {}", kernel_timed, readings.len());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn probe(args: &Args) -> Result<ExitCode, Box<dyn Error>> {
+    let mut sensor = open_sensor(args)?;
+    let count = args.count.unwrap_or(5).max(1);
+
+    // long enough for the ~38 ms pulse some modules send when nothing is in range
+    let timeout = Some(Duration::from_millis(25));
+    let (mut toggled, mut never_rose, mut stuck_high) = (0, 0, 0);
+    paced(count, MIN_MEASUREMENT_CYCLE, || {
+        match sensor.measurement(timeout) {
+            Ok(res) => {
+                toggled += 1;
+                println!("echo high for {:?} ({:.2})", res.echo_high, res.distance);
+            }
+            Err(err @ HcSr04Error::OutOfRange { .. }) => {
+                toggled += 1;
+                println!("echo toggled, {}", err);
+            }
+            Err(HcSr04Error::NoEchoStart { .. }) => {
+                never_rose += 1;
+                println!("echo never went high");
+            }
+            Err(HcSr04Error::EchoNeverEnded { .. }) => {
+                stuck_high += 1;
+                println!("echo went high but never came back down");
+            }
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    })?;
+
+    if toggled == count {
+        println!("ok: the echo line follows the trigger");
+        return Ok(ExitCode::SUCCESS)
+    }
+    if never_rose == count {
+        println!("the echo line never went high: check the echo wire, the module's 5 V supply and the trigger pin");
+    } else if stuck_high == count {
+        println!("the echo line stays high: check for a short to 3.3 V or a floating line, try a pull-down");
+    } else {
+        println!("intermittent: {} of {} pings came back, check for loose connections", toggled, count);
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+fn daemon(args: &Args) -> Result<ExitCode, Box<dyn Error>> {
+    let sensor = open_sensor(args)?;
+    eprintln!("hcsr04: serving {:?} on {}", args.sensor, args.socket);
+    Daemon::new().sensor(args.sensor.as_str(), sensor).interval(args.period).run(&args.socket)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn list_chips() -> Result<ExitCode, Box<dyn Error>> {
+    for chip in gpio_chips()? {
+        let header = match HEADER_CHIP_LABELS.contains(&chip.label()) {
+            true => "  (40-pin header)",
+            false => "",
+        };
+        println!("{}\t{}\t{} lines{}", chip.path().display(), chip.label(), chip.num_lines(), header);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("hcsr04: {}\n\n{}", msg, USAGE);
+            return ExitCode::from(2)
+        }
+    };
+
+    let res = match args.command.as_str() {
+        "read" => read(&args),
+        "stats" => stats(&args),
+        "probe" => probe(&args),
+        "list-chips" => list_chips(),
+        "daemon" => daemon(&args),
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            Ok(ExitCode::SUCCESS)
+        }
+        command => {
+            eprintln!("hcsr04: unknown command {:?}\n\n{}", command, USAGE);
+            return ExitCode::from(2)
+        }
+    };
+
+    match res {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("hcsr04: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use crate::backend::{Bias, CdevBackend, CdevMultiBackend, EchoBackend, MultiEchoBackend, SinglePinBackend};
+use crate::{
+    find_chip_by_label, find_header_chip, Distance, LengthUnit, HcSr04, HcSr04Error, SharedTrigger, SpeedOfSound,
+    DEFAULT_TIMEOUT_MICROSECS,
+};
+use gpio_cdev::{Chip, LineRequestFlags};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The kernel keeps 32 bytes of consumer label, including the NUL.
+const MAX_CONSUMER_LEN: usize = 31;
+
+#[derive(Debug)]
+enum ChipSource {
+    Header,
+    Path(PathBuf),
+    Label(String),
+    Chip(Chip),
+}
+
+/// A usable range: a finite, non-negative minimum and, if there is one, a
+/// finite maximum above it.
+pub(crate) fn check_range(min: Distance, max: Option<Distance>) -> Result<(), HcSr04Error> {
+    if !min.is_finite() || min < Distance::default() {
+        return Err(HcSr04Error::InvalidConfig(format!("minimum range must be a non-negative distance, got {}", min)))
+    }
+    if let Some(max) = max
+        && (!max.is_finite() || max <= min)
+    {
+        return Err(HcSr04Error::InvalidConfig(format!("maximum range {} must be above the minimum range {}", max, min)))
+    }
+    Ok(())
+}
+
+/// Everything about an `HcSr04` that isn't the GPIO backend itself.
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    /// minimum distance reading that will not be ignored
+    pub(crate) dist_threshold: Distance,
+    pub(crate) max_range: Option<Distance>,
+    pub(crate) speed_of_sound: SpeedOfSound,
+    pub(crate) trigger_pulse: Duration,
+    pub(crate) settle: Duration,
+    pub(crate) default_timeout: Duration,
+    /// turn out-of-range readings into the nearest limit instead of an error
+    pub(crate) clamp: bool,
+    /// extra pings after a timed out one
+    pub(crate) retries: u32,
+    /// readings this close to a range limit are flagged
+    pub(crate) near_margin: Distance,
+}
+
+impl Settings {
+    pub(crate) fn new(dist_threshold: Distance) -> Self {
+        Self {
+            dist_threshold,
+            max_range: None,
+            speed_of_sound: SpeedOfSound::default(),
+            trigger_pulse: Duration::from_micros(10),
+            settle: Duration::from_micros(2),
+            default_timeout: Duration::from_micros(DEFAULT_TIMEOUT_MICROSECS),
+            clamp: false,
+            retries: 0,
+            near_margin: Distance::cm(1.0),
+        }
+    }
+
+    /// Settle low, pulse high, back low.
+    pub(crate) fn pulse_trigger<F>(&self, mut set_trigger: F) -> Result<(), HcSr04Error>
+    where
+        F: FnMut(bool) -> Result<(), HcSr04Error>,
+    {
+        set_trigger(false)?;
+
+        sleep(self.settle);
+
+        set_trigger(true)?;
+
+        sleep(self.trigger_pulse);
+
+        set_trigger(false)
+    }
+
+    /// Distance for an echo high time, checked against the valid range. With
+    /// clamping on, the flag says whether it had to be pulled back into it.
+    pub(crate) fn echo_to_distance(&self, echo_high: Duration) -> Result<(Distance, bool), HcSr04Error> {
+        let distance = self.speed_of_sound.velocity() * echo_high / 2.0;
+        let distance = distance.to(LengthUnit::Cm);
+
+        let min = self.dist_threshold;
+        let max = self.max_range;
+        let limit = match max {
+            Some(max) if distance > max => max,
+            _ if distance < min => min,
+            _ => return Ok((distance, false)),
+        };
+        if !self.clamp {
+            return Err(HcSr04Error::OutOfRange { distance, min, max })
+        }
+        Ok((limit.to(LengthUnit::Cm), true))
+    }
+
+    /// Whether `distance` is within the margin of either range limit.
+    pub(crate) fn near_threshold(&self, distance: Distance) -> bool {
+        let near = |limit: Distance| (distance - limit).abs() <= self.near_margin;
+        near(self.dist_threshold) || self.max_range.is_some_and(near)
+    }
+
+    /// How long to wait for the echo, given the `timeout` passed to `dist_*`.
+    pub(crate) fn effective_timeout(&self, timeout: Option<Duration>) -> Duration {
+        match timeout {
+            Some(val) => 2 * val,
+            None => self.default_timeout
+        }
+    }
+}
+
+/// The options of an [`HcSr04Builder`] as plain data, e.g. for a config file.
+/// Fields left out when deserializing keep the builder's defaults.
+///
+/// An already opened [`Chip`] can't be part of it, set one on the builder
+/// afterwards if needed.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct HcSr04Config {
+    /// chip device path, see [`HcSr04Builder::chip_path`]
+    pub chip_path: Option<PathBuf>,
+    /// chip label, used instead of `chip_path` if both are set
+    pub chip_label: Option<String>,
+    /// trigger and echo line offsets, the same line twice for a single-pin sensor
+    pub pins: Option<(u32, u32)>,
+    pub trigger_consumer: String,
+    pub echo_consumer: String,
+    pub echo_bias: Bias,
+    pub min_range: Distance,
+    pub max_range: Option<Distance>,
+    pub trigger_pulse: Duration,
+    pub settle_delay: Duration,
+    pub default_timeout: Duration,
+    pub clamp_to_range: bool,
+    pub retries: u32,
+    pub near_threshold_margin: Distance,
+    pub speed_of_sound: SpeedOfSound,
+}
+
+impl Default for HcSr04Config {
+    fn default() -> Self {
+        HcSr04Builder::default().config()
+    }
+}
+
+impl From<HcSr04Config> for HcSr04Builder {
+    /// Nothing is checked until the builder builds.
+    fn from(config: HcSr04Config) -> Self {
+        let chip = match (config.chip_label, config.chip_path) {
+            (Some(label), _) => ChipSource::Label(label),
+            (None, Some(path)) => ChipSource::Path(path),
+            (None, None) => ChipSource::Header,
+        };
+        Self {
+            chip,
+            pins: config.pins,
+            trigger_consumer: config.trigger_consumer,
+            echo_consumer: config.echo_consumer,
+            echo_bias: config.echo_bias,
+            settings: Settings {
+                dist_threshold: config.min_range,
+                max_range: config.max_range,
+                speed_of_sound: config.speed_of_sound,
+                trigger_pulse: config.trigger_pulse,
+                settle: config.settle_delay,
+                default_timeout: config.default_timeout,
+                clamp: config.clamp_to_range,
+                retries: config.retries,
+                near_margin: config.near_threshold_margin,
+            },
+        }
+    }
+}
+
+/// Configures an [`HcSr04`] beyond what [`HcSr04::new`] exposes.
+///
+/// Only the pins are required, everything else defaults to what `HcSr04::new` does.
+#[derive(Debug)]
+pub struct HcSr04Builder {
+    chip: ChipSource,
+    pins: Option<(u32, u32)>,
+    trigger_consumer: String,
+    echo_consumer: String,
+    echo_bias: Bias,
+    settings: Settings,
+}
+
+impl Default for HcSr04Builder {
+    fn default() -> Self {
+        Self {
+            chip: ChipSource::Header,
+            pins: None,
+            trigger_consumer: "hc-sr04-trigger".to_string(),
+            echo_consumer: "hc-sr04-echo".to_string(),
+            echo_bias: Bias::AsIs,
+            settings: Settings::new(Distance::cm(0.0)),
+        }
+    }
+}
+
+impl HcSr04Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from a deserialized configuration, same as `HcSr04Builder::from(config)`.
+    pub fn from_config(config: HcSr04Config) -> Self {
+        config.into()
+    }
+
+    /// The options set so far. An already opened chip is left out.
+    pub fn config(&self) -> HcSr04Config {
+        let settings = &self.settings;
+        HcSr04Config {
+            chip_path: match &self.chip {
+                ChipSource::Path(path) => Some(path.clone()),
+                _ => None,
+            },
+            chip_label: match &self.chip {
+                ChipSource::Label(label) => Some(label.clone()),
+                _ => None,
+            },
+            pins: self.pins,
+            trigger_consumer: self.trigger_consumer.clone(),
+            echo_consumer: self.echo_consumer.clone(),
+            echo_bias: self.echo_bias,
+            min_range: settings.dist_threshold,
+            max_range: settings.max_range,
+            trigger_pulse: settings.trigger_pulse,
+            settle_delay: settings.settle,
+            default_timeout: settings.default_timeout,
+            clamp_to_range: settings.clamp,
+            retries: settings.retries,
+            near_threshold_margin: settings.near_margin,
+            speed_of_sound: settings.speed_of_sound,
+        }
+    }
+
+    /// Opens the chip at `path`, e.g. `/dev/gpiochip0`. Defaults to [`find_header_chip`].
+    pub fn chip_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.chip = ChipSource::Path(path.into());
+        self
+    }
+
+    /// Opens the chip labelled `label`, e.g. `"pinctrl-rp1"`.
+    pub fn chip_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.chip = ChipSource::Label(label.into());
+        self
+    }
+
+    /// Uses an already opened chip.
+    pub fn chip(mut self, chip: Chip) -> Self {
+        self.chip = ChipSource::Chip(chip);
+        self
+    }
+
+    /// Line offsets of the trigger and echo pins on the chip.
+    pub fn pins(mut self, trig: u32, echo: u32) -> Self {
+        self.pins = Some((trig, echo));
+        self
+    }
+
+    /// The one line of a single-pin sensor, for [`HcSr04Builder::build_single_pin`].
+    pub fn signal_pin(mut self, pin: u32) -> Self {
+        self.pins = Some((pin, pin));
+        self
+    }
+
+    /// Consumer labels shown by `gpioinfo` for the two lines.
+    pub fn consumers<T: Into<String>, E: Into<String>>(mut self, trigger: T, echo: E) -> Self {
+        self.trigger_consumer = trigger.into();
+        self.echo_consumer = echo.into();
+        self
+    }
+
+    /// How long the trigger is held high, 10 µs by default as per the datasheet.
+    pub fn trigger_pulse(mut self, width: Duration) -> Self {
+        self.settings.trigger_pulse = width;
+        self
+    }
+
+    /// How long the trigger is held low before the pulse, 2 µs by default.
+    pub fn settle_delay(mut self, delay: Duration) -> Self {
+        self.settings.settle = delay;
+        self
+    }
+
+    /// Timeout used when `dist_*` is passed `None`.
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.default_timeout = timeout;
+        self
+    }
+
+    /// Readings below this are rejected, like `dist_threshold` in [`HcSr04::new`].
+    pub fn min_range(mut self, min: Distance) -> Self {
+        self.settings.dist_threshold = min;
+        self
+    }
+
+    /// Readings above this are rejected. Unlimited by default.
+    pub fn max_range(mut self, max: Distance) -> Self {
+        self.settings.max_range = Some(max);
+        self
+    }
+
+    /// Out-of-range readings come back as the nearest range limit, flagged in
+    /// the [`Measurement`](crate::Measurement), instead of as an error. Off by default.
+    pub fn clamp_to_range(mut self, clamp: bool) -> Self {
+        self.settings.clamp = clamp;
+        self
+    }
+
+    /// Pings up to `retries` more times when the echo times out. None by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.settings.retries = retries;
+        self
+    }
+
+    /// Readings this close to a range limit are flagged as near the threshold, 1 cm by default.
+    pub fn near_threshold_margin(mut self, margin: Distance) -> Self {
+        self.settings.near_margin = margin;
+        self
+    }
+
+    pub fn speed_of_sound<S: Into<SpeedOfSound>>(mut self, speed_of_sound: S) -> Self {
+        self.settings.speed_of_sound = speed_of_sound.into();
+        self
+    }
+
+    /// Pull resistor on the echo line, left as configured by default.
+    pub fn echo_bias(mut self, bias: Bias) -> Self {
+        self.echo_bias = bias;
+        self
+    }
+
+    fn validate(&self) -> Result<(), HcSr04Error> {
+        let invalid = |msg: String| Err(HcSr04Error::InvalidConfig(msg));
+
+        for (name, consumer) in [("trigger", &self.trigger_consumer), ("echo", &self.echo_consumer)] {
+            if consumer.is_empty() || consumer.len() > MAX_CONSUMER_LEN {
+                return invalid(format!(
+                    "{} consumer label must be 1 to {} bytes, got {:?}",
+                    name, MAX_CONSUMER_LEN, consumer
+                ))
+            }
+        }
+
+        let settings = &self.settings;
+        if settings.trigger_pulse.is_zero() {
+            return invalid("trigger pulse width must be non-zero".to_string())
+        }
+        if settings.default_timeout.is_zero() {
+            return invalid("default timeout must be non-zero".to_string())
+        }
+
+        check_range(settings.dist_threshold, settings.max_range)?;
+
+        let margin = settings.near_margin;
+        if !margin.is_finite() || margin < Distance::default() {
+            return invalid(format!("near-threshold margin must be a non-negative distance, got {}", margin))
+        }
+
+        let speed = settings.speed_of_sound.meters_per_sec();
+        if !speed.is_finite() || speed <= 0.0 {
+            return invalid(format!("speed of sound must be positive, got {} m/s", speed))
+        }
+
+        Ok(())
+    }
+
+    fn open_chip(chip: ChipSource) -> Result<Chip, HcSr04Error> {
+        match chip {
+            ChipSource::Header => find_header_chip(),
+            ChipSource::Label(label) => find_chip_by_label(&label),
+            ChipSource::Chip(chip) => Ok(chip),
+            ChipSource::Path(path) => match Chip::new(&path) {
+                Ok(chip) => Ok(chip),
+                Err(source) => Err(HcSr04Error::ChipOpen { path, source })
+            },
+        }
+    }
+
+    pub fn build(self) -> Result<HcSr04, HcSr04Error> {
+        self.validate()?;
+
+        let (trig, echo) = match self.pins {
+            Some(pins) => pins,
+            None => return Err(HcSr04Error::InvalidConfig("trigger and echo pins must be set".to_string()))
+        };
+        if trig == echo {
+            return Err(HcSr04Error::InvalidConfig(format!(
+                "trigger and echo are both line {}, use build_single_pin for one-pin sensors",
+                trig
+            )))
+        }
+
+        let mut chip = Self::open_chip(self.chip)?;
+
+        let trig_line = chip.get_line(trig)
+            .map_err(|source| HcSr04Error::line_request(trig, source))?;
+
+        let echo_line = chip.get_line(echo)
+            .map_err(|source| HcSr04Error::line_request(echo, source))?;
+
+        let trig_handle = trig_line.request(LineRequestFlags::OUTPUT, 0, &self.trigger_consumer)
+            .map_err(|source| HcSr04Error::line_request(trig, source))?;
+
+        let backend = CdevBackend::with_echo_options(trig_handle, echo_line, &self.echo_consumer, self.echo_bias)?;
+        Ok(HcSr04::from_parts(backend, self.settings))
+    }
+
+    /// Builds a sensor with one signal pin for trigger and echo, set with
+    /// [`HcSr04Builder::signal_pin`]. The trigger consumer label is used for the line.
+    pub fn build_single_pin(self) -> Result<HcSr04<SinglePinBackend>, HcSr04Error> {
+        self.validate()?;
+
+        let pin = match self.pins {
+            Some((trig, echo)) if trig == echo => trig,
+            _ => return Err(HcSr04Error::InvalidConfig("signal pin must be set".to_string()))
+        };
+
+        let line = Self::open_chip(self.chip)?.get_line(pin)
+            .map_err(|source| HcSr04Error::line_request(pin, source))?;
+
+        let backend = SinglePinBackend::with_options(line, &self.trigger_consumer, self.echo_bias)?;
+        Ok(HcSr04::from_parts(backend, self.settings))
+    }
+
+    /// Applies the configuration to another backend, e.g. a [`MockBackend`](crate::MockBackend).
+    /// Chip, pins, consumers and bias are ignored.
+    pub fn build_with_backend<B: EchoBackend>(self, backend: B) -> Result<HcSr04<B>, HcSr04Error> {
+        self.validate()?;
+        Ok(HcSr04::from_parts(backend, self.settings))
+    }
+
+    /// Builds a [`SharedTrigger`] firing the modules on `echoes` through the
+    /// line `trig`. The lines are passed here rather than set with
+    /// [`HcSr04Builder::pins`], which is ignored; the echo consumer and bias
+    /// apply to every echo line.
+    pub fn build_shared_trigger(self, trig: u32, echoes: &[u32]) -> Result<SharedTrigger, HcSr04Error> {
+        self.validate()?;
+
+        if echoes.is_empty() {
+            return Err(HcSr04Error::InvalidConfig("at least one echo line is needed".to_string()))
+        }
+        for (i, echo) in echoes.iter().enumerate() {
+            if *echo == trig || echoes[..i].contains(echo) {
+                return Err(HcSr04Error::InvalidConfig(format!("line {} is used more than once", echo)))
+            }
+        }
+
+        let mut chip = Self::open_chip(self.chip)?;
+
+        let trig_line = chip.get_line(trig)
+            .map_err(|source| HcSr04Error::line_request(trig, source))?;
+
+        let echo_lines = echoes.iter()
+            .map(|&echo| chip.get_line(echo)
+                .map_err(|source| HcSr04Error::line_request(echo, source)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let trig_handle = trig_line.request(LineRequestFlags::OUTPUT, 0, &self.trigger_consumer)
+            .map_err(|source| HcSr04Error::line_request(trig, source))?;
+
+        let backend = CdevMultiBackend::with_echo_options(trig_handle, &echo_lines, &self.echo_consumer, self.echo_bias)?;
+        Ok(SharedTrigger::from_parts(backend, self.settings))
+    }
+
+    /// [`HcSr04Builder::build_shared_trigger`] on another backend, e.g. a
+    /// [`MockMultiBackend`](crate::MockMultiBackend). Chip, pins, consumers and
+    /// bias are ignored.
+    pub fn build_shared_trigger_with_backend<B: MultiEchoBackend>(self, backend: B) -> Result<SharedTrigger<B>, HcSr04Error> {
+        self.validate()?;
+        Ok(SharedTrigger::from_parts(backend, self.settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, MockBackend, MockEcho, MockMultiBackend};
+
+    #[test]
+    fn config_round_trip() {
+        let builder = HcSr04Builder::new()
+            .chip_label("pinctrl-rp1")
+            .pins(23, 24)
+            .consumers("front-trig", "front-echo")
+            .echo_bias(Bias::PullDown)
+            .min_range(Distance::cm(5.0))
+            .max_range(Distance::cm(250.0))
+            .trigger_pulse(Duration::from_micros(12))
+            .default_timeout(Duration::from_millis(20))
+            .clamp_to_range(true)
+            .retries(2)
+            .speed_of_sound(Environment::new(30.0));
+        let config = builder.config();
+        assert_eq!(config.chip_label.as_deref(), Some("pinctrl-rp1"));
+        assert_eq!(config.chip_path, None);
+        assert_eq!(config.pins, Some((23, 24)));
+        assert_eq!(config.echo_bias, Bias::PullDown);
+        assert_eq!(config.speed_of_sound, SpeedOfSound::Air(Environment::new(30.0)));
+        assert_eq!(HcSr04Builder::from_config(config.clone()).config(), config);
+    }
+
+    #[test]
+    fn default_config_matches_builder() {
+        let config = HcSr04Config::default();
+        assert_eq!(config.trigger_consumer, "hc-sr04-trigger");
+        assert_eq!(config.trigger_pulse, Duration::from_micros(10));
+        assert_eq!(config.speed_of_sound, SpeedOfSound::default());
+        assert_eq!(config.pins, None);
+    }
+
+    #[test]
+    fn built_from_config() {
+        let config = HcSr04Config {
+            min_range: Distance::cm(10.0),
+            max_range: Some(Distance::cm(100.0)),
+            clamp_to_range: true,
+            ..HcSr04Config::default()
+        };
+        let backend = MockBackend::with_script([MockEcho::from_distance(Distance::cm(150.0))]);
+        let mut hcsr04 = HcSr04Builder::from(config).build_with_backend(backend).unwrap();
+        assert_eq!(hcsr04.min_range(), Distance::cm(10.0));
+
+        let res = hcsr04.measurement(Some(Duration::from_millis(10))).unwrap();
+        assert!(res.flags.clamped);
+        assert_eq!(res.distance, Distance::cm(100.0));
+
+        // validated like any other builder
+        let config = HcSr04Config { trigger_consumer: String::new(), ..HcSr04Config::default() };
+        let err = HcSr04Builder::from(config).build_with_backend(MockBackend::new());
+        assert!(matches!(err, Err(HcSr04Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn shared_trigger_gets_the_builder_options() {
+        let backend = MockMultiBackend::new(vec![
+            MockBackend::with_script([MockEcho::from_distance(Distance::cm(60.0))]),
+            MockBackend::with_script([MockEcho::from_distance(Distance::cm(140.0))]),
+        ]);
+        let mut shared = HcSr04Builder::new()
+            .min_range(Distance::cm(5.0))
+            .max_range(Distance::cm(100.0))
+            .speed_of_sound(Environment::new(0.0))
+            .build_shared_trigger_with_backend(backend)
+            .unwrap();
+        assert_eq!(shared.min_range(), Distance::cm(5.0));
+        assert_eq!(shared.max_range(), Some(Distance::cm(100.0)));
+        assert_eq!(shared.speed_of_sound(), SpeedOfSound::Air(Environment::new(0.0)));
+
+        // echoes timed for 343 m/s come out shorter in cold air
+        let results = shared.dist_cm(Some(Duration::from_millis(10))).unwrap();
+        let near = results[0].as_ref().unwrap().as_cm();
+        assert!(near < 60.0 && near > 55.0, "{}", near);
+        assert!(matches!(results[1], Err(HcSr04Error::OutOfRange { .. })));
+
+        let err = HcSr04Builder::new().trigger_pulse(Duration::ZERO).build_shared_trigger_with_backend(MockMultiBackend::default());
+        assert!(matches!(err, Err(HcSr04Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn range_setters_are_validated() {
+        let mut hcsr04 = HcSr04::with_backend(MockBackend::new(), Distance::cm(2.0));
+        hcsr04.set_max_range(Some(Distance::cm(300.0))).unwrap();
+        assert!(hcsr04.set_min_range(Distance::cm(-1.0)).is_err());
+        assert!(hcsr04.set_min_range(Distance::cm(f64::NAN)).is_err());
+        assert!(hcsr04.set_min_range(Distance::cm(300.0)).is_err());
+        assert!(hcsr04.set_max_range(Some(Distance::cm(2.0))).is_err());
+        assert!(hcsr04.set_max_range(Some(Distance::cm(f64::INFINITY))).is_err());
+        assert_eq!(hcsr04.min_range(), Distance::cm(2.0));
+        assert_eq!(hcsr04.max_range(), Some(Distance::cm(300.0)));
+
+        hcsr04.set_min_range(Distance::cm(10.0)).unwrap();
+        hcsr04.set_max_range(None).unwrap();
+        assert_eq!(hcsr04.min_range(), Distance::cm(10.0));
+
+        let mut shared = SharedTrigger::with_backend(MockMultiBackend::default(), Distance::cm(2.0));
+        assert!(shared.set_max_range(Some(Distance::cm(1.0))).is_err());
+        assert!(shared.set_min_range(Distance::cm(-2.0)).is_err());
+        assert_eq!(shared.max_range(), None);
+        assert_eq!(shared.min_range(), Distance::cm(2.0));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{Distance, EchoBackend, HcSr04, HcSr04Error};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How a burst's readings are boiled down to one distance.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Aggregate {
+    /// median of all readings
+    #[default]
+    Median,
+    /// mean of the readings within `k` scaled median absolute deviations of the median
+    Mad { k: f64 },
+    /// mean after dropping `fraction` of the readings from each end
+    TrimmedMean { fraction: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BurstConfig {
+    /// number of pings
+    pub count: usize,
+    /// pause between pings
+    pub gap: Duration,
+    /// per-ping timeout, as for `dist_cm`
+    pub timeout: Option<Duration>,
+    pub aggregate: Aggregate,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        Self {
+            count: 5,
+            gap: crate::MIN_MEASUREMENT_CYCLE,
+            timeout: None,
+            aggregate: Aggregate::default(),
+        }
+    }
+}
+
+impl BurstConfig {
+    pub fn new(count: usize, gap: Duration, aggregate: Aggregate) -> Self {
+        Self {
+            count,
+            gap,
+            timeout: None,
+            aggregate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BurstResult {
+    pub distance: Distance,
+    /// standard deviation of the readings that went into `distance`, or the
+    /// scaled median absolute deviation for [`Aggregate::Median`]
+    pub spread: Distance,
+    /// readings that went into `distance`
+    pub valid: usize,
+    /// readings thrown out as outliers
+    pub outliers: usize,
+    /// pings that timed out or were out of range
+    pub missed: usize,
+}
+
+/// Result of [`aggregate`], in whatever unit the input was in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Aggregated {
+    pub value: f64,
+    pub spread: f64,
+    pub kept: usize,
+}
+
+fn median_sorted(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Median of `values`, `None` if empty.
+pub fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    Some(median_sorted(&sorted))
+}
+
+/// Median absolute deviation, scaled by 1.4826 to be comparable to a standard
+/// deviation for normally distributed noise.
+fn scaled_mad(sorted: &[f64], med: f64) -> f64 {
+    let mut deviations: Vec<f64> = sorted.iter().map(|val| (val - med).abs()).collect();
+    deviations.sort_by(f64::total_cmp);
+    1.4826 * median_sorted(&deviations)
+}
+
+fn mean_and_std_dev(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|val| (val - mean).powi(2)).sum::<f64>() / n;
+    (mean, var.sqrt())
+}
+
+/// Combines readings with the given method. `None` if nothing is left to combine.
+pub fn aggregate(values: &[f64], method: Aggregate) -> Option<Aggregated> {
+    let mut sorted: Vec<f64> = values.iter().copied().filter(|val| val.is_finite()).collect();
+    if sorted.is_empty() {
+        return None
+    }
+    sorted.sort_by(f64::total_cmp);
+
+    let kept = match method {
+        Aggregate::Median => {
+            let med = median_sorted(&sorted);
+            return Some(Aggregated {
+                value: med,
+                spread: scaled_mad(&sorted, med),
+                kept: sorted.len(),
+            })
+        }
+        Aggregate::Mad { k } => {
+            let med = median_sorted(&sorted);
+            let mad = scaled_mad(&sorted, med);
+            let kept: Vec<f64> = sorted.iter().copied().filter(|val| (val - med).abs() <= k * mad).collect();
+            if kept.is_empty() {
+                // k too tight to keep anything, the median is the best we have
+                return aggregate(&sorted, Aggregate::Median)
+            }
+            kept
+        }
+        Aggregate::TrimmedMean { fraction } => {
+            // always leave at least one reading in the middle
+            let trim = ((sorted.len() as f64) * fraction.clamp(0.0, 0.5)) as usize;
+            let trim = trim.min((sorted.len() - 1) / 2);
+            sorted[trim..sorted.len() - trim].to_vec()
+        }
+    };
+
+    let (value, spread) = mean_and_std_dev(&kept);
+    Some(Aggregated {
+        value,
+        spread,
+        kept: kept.len(),
+    })
+}
+
+impl<B: EchoBackend> HcSr04<B> {
+    /// Pings `config.count` times and combines the readings, in cm.
+    ///
+    /// Timeouts and out-of-range readings are skipped; any other error ends the
+    /// burst. If not a single reading came back the last error is returned.
+    pub fn burst(&mut self, config: &BurstConfig) -> Result<BurstResult, HcSr04Error> {
+        if config.count == 0 {
+            return Err(HcSr04Error::InvalidConfig("burst needs at least one ping".to_string()))
+        }
+
+        let mut readings = Vec::with_capacity(config.count);
+        let mut last_err = None;
+        for i in 0..config.count {
+            if i > 0 {
+                sleep(config.gap);
+            }
+            match self.dist(config.timeout) {
+                Ok(dist) => readings.push(dist),
+                Err(err @ (HcSr04Error::NoEchoStart { .. }
+                    | HcSr04Error::EchoNeverEnded { .. }
+                    | HcSr04Error::OutOfRange { .. })) => last_err = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let missed = config.count - readings.len();
+        match aggregate(&readings, config.aggregate) {
+            Some(res) => Ok(BurstResult {
+                distance: Distance::cm(res.value),
+                spread: Distance::cm(res.spread),
+                valid: res.kept,
+                outliers: readings.len() - res.kept,
+                missed,
+            }),
+            None => Err(last_err.unwrap_or(HcSr04Error::NoEchoStart { timeout: self.settings.default_timeout })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockBackend, MockEcho};
+
+    #[test]
+    fn median_odd_and_even() {
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), Some(2.5));
+
+        let res = aggregate(&[3.0, 1.0, 2.0], Aggregate::Median).unwrap();
+        assert_eq!(res, Aggregated { value: 2.0, spread: 1.4826, kept: 3 });
+        let res = aggregate(&[4.0, 1.0, 3.0, 2.0], Aggregate::Median).unwrap();
+        assert_eq!((res.value, res.kept), (2.5, 4));
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(median(&[]), None);
+        for method in [Aggregate::Median, Aggregate::Mad { k: 3.0 }, Aggregate::TrimmedMean { fraction: 0.2 }] {
+            assert_eq!(aggregate(&[], method), None);
+            // non-finite readings are dropped before combining
+            assert_eq!(aggregate(&[f64::NAN, f64::INFINITY], method), None);
+        }
+    }
+
+    #[test]
+    fn mad_drops_outliers() {
+        let res = aggregate(&[10.0, 10.5, 11.0, 50.0], Aggregate::Mad { k: 3.0 }).unwrap();
+        assert_eq!((res.value, res.kept), (10.5, 3));
+    }
+
+    #[test]
+    fn mad_of_zero() {
+        // more than half the readings agree, so only they are kept
+        let res = aggregate(&[5.0, 5.0, 5.0, 9.0], Aggregate::Mad { k: 3.0 }).unwrap();
+        assert_eq!(res, Aggregated { value: 5.0, spread: 0.0, kept: 3 });
+
+        // nothing lies on the median, so a zero k keeps nothing and the median is used
+        let res = aggregate(&[1.0, 3.0], Aggregate::Mad { k: 0.0 }).unwrap();
+        assert_eq!(res, aggregate(&[1.0, 3.0], Aggregate::Median).unwrap());
+        assert_eq!((res.value, res.kept), (2.0, 2));
+    }
+
+    #[test]
+    fn trimmed_mean_clamps_fraction() {
+        let values = [100.0, 1.0, 4.0, 2.0, 3.0];
+        let trimmed = |fraction| aggregate(&values, Aggregate::TrimmedMean { fraction }).map(|res| (res.value, res.kept));
+        assert_eq!(trimmed(0.2), Some((3.0, 3)));
+        // too much trimming still leaves the middle reading
+        assert_eq!(trimmed(0.9), Some((3.0, 1)));
+        // negative trimming keeps everything
+        assert_eq!(trimmed(-1.0), Some((22.0, 5)));
+
+        let res = aggregate(&[1.0, 2.0, 3.0, 100.0], Aggregate::TrimmedMean { fraction: 0.5 }).unwrap();
+        assert_eq!((res.value, res.kept), (2.5, 2));
+    }
+
+    fn sensor<I: IntoIterator<Item = MockEcho>>(script: I) -> HcSr04<MockBackend> {
+        HcSr04::with_backend(MockBackend::with_script(script), Distance::cm(2.0))
+    }
+
+    #[test]
+    fn burst_counts_missed_and_outliers() {
+        let mut hcsr04 = sensor([
+            MockEcho::from_distance(Distance::cm(40.0)),
+            MockEcho::NoEcho,
+            MockEcho::from_distance(Distance::cm(41.0)),
+            MockEcho::from_distance(Distance::cm(1.0)),
+            MockEcho::from_distance(Distance::cm(200.0)),
+            MockEcho::NoFall,
+            MockEcho::from_distance(Distance::cm(40.5)),
+        ]);
+        let config = BurstConfig {
+            timeout: Some(Duration::from_millis(20)),
+            ..BurstConfig::new(7, Duration::ZERO, Aggregate::Mad { k: 3.0 })
+        };
+
+        let res = hcsr04.burst(&config).unwrap();
+        assert_eq!((res.valid, res.outliers, res.missed), (3, 1, 3));
+        assert!((res.distance.as_cm() - 40.5).abs() < 0.01, "{:?}", res.distance);
+        assert_eq!(hcsr04.backend().trigger_pulses(), 7);
+    }
+
+    #[test]
+    fn burst_without_readings() {
+        let mut hcsr04 = sensor([MockEcho::NoEcho, MockEcho::NoFall]);
+        let config = BurstConfig::new(2, Duration::ZERO, Aggregate::Median);
+        assert!(matches!(hcsr04.burst(&config), Err(HcSr04Error::EchoNeverEnded { .. })));
+
+        let config = BurstConfig::new(0, Duration::ZERO, Aggregate::Median);
+        assert!(matches!(hcsr04.burst(&config), Err(HcSr04Error::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::HcSr04Error;
+use gpio_cdev::Chip;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+/// Labels of the controllers wired to the 40-pin header, most recent board first.
+pub const HEADER_CHIP_LABELS: &[&str] = &[
+    "pinctrl-rp1",     // Pi 5
+    "pinctrl-bcm2711", // Pi 4, CM4, Pi 400
+    "pinctrl-bcm2835", // Pi 3 and older, Zero
+];
+
+/// The `N` of a `gpiochipN` path.
+fn chip_number(path: &Path) -> Option<u32> {
+    path.file_name()?.to_str()?.strip_prefix("gpiochip")?.parse().ok()
+}
+
+/// Sorts by chip number, so `gpiochip2` comes before `gpiochip10`. Names
+/// without a number go last, by name.
+fn sort_chip_paths(paths: &mut [PathBuf]) {
+    paths.sort_by(|a, b| {
+        let key = |path: &PathBuf| chip_number(path).unwrap_or(u32::MAX);
+        key(a).cmp(&key(b)).then_with(|| a.cmp(b))
+    });
+}
+
+/// Index of the label that comes first in [`HEADER_CHIP_LABELS`], if any.
+fn header_chip_index<'a, I: IntoIterator<Item = &'a str>>(labels: I) -> Option<usize> {
+    let labels: Vec<&str> = labels.into_iter().collect();
+    HEADER_CHIP_LABELS
+        .iter()
+        .find_map(|header| labels.iter().position(|label| label == header))
+}
+
+/// Opens every `/dev/gpiochip*`, in chip number order. Chips that fail to open are skipped.
+pub fn gpio_chips() -> Result<Vec<Chip>, HcSr04Error> {
+    let entries = read_dir("/dev").map_err(|err| HcSr04Error::ChipOpen {
+        path: PathBuf::from("/dev"),
+        source: err.into(),
+    })?;
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("gpiochip")))
+        .collect();
+    sort_chip_paths(&mut paths);
+
+    Ok(paths.iter().filter_map(|path| Chip::new(path).ok()).collect())
+}
+
+/// Opens the first chip whose label is `label`, e.g. `"pinctrl-rp1"`.
+pub fn find_chip_by_label(label: &str) -> Result<Chip, HcSr04Error> {
+    match gpio_chips()?.into_iter().find(|chip| chip.label() == label) {
+        Some(chip) => Ok(chip),
+        None => Err(HcSr04Error::ChipNotFound(format!("label {:?}", label)))
+    }
+}
+
+/// Finds the controller driving the board's main GPIO header, see [`HEADER_CHIP_LABELS`].
+pub fn find_header_chip() -> Result<Chip, HcSr04Error> {
+    let mut chips = gpio_chips()?;
+    match header_chip_index(chips.iter().map(|chip| chip.label())) {
+        Some(pos) => Ok(chips.swap_remove(pos)),
+        None => Err(HcSr04Error::ChipNotFound(format!("any of {}", HEADER_CHIP_LABELS.join(", "))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_by_chip_number() {
+        let mut paths: Vec<PathBuf> = ["gpiochip10", "gpiochip2", "gpiochip0", "gpiochip1", "gpiochip-mock", "gpiochip11"]
+            .iter()
+            .map(|name| Path::new("/dev").join(name))
+            .collect();
+        sort_chip_paths(&mut paths);
+        let names: Vec<_> = paths.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["gpiochip0", "gpiochip1", "gpiochip2", "gpiochip10", "gpiochip11", "gpiochip-mock"]);
+
+        assert_eq!(chip_number(Path::new("/dev/gpiochip4")), Some(4));
+        assert_eq!(chip_number(Path::new("/dev/gpiochip")), None);
+        assert_eq!(chip_number(Path::new("/dev/ttyAMA0")), None);
+    }
+
+    #[test]
+    fn matches_header_labels() {
+        // the newest board's controller wins, wherever it is
+        assert_eq!(header_chip_index(["pinctrl-bcm2711", "rp1-pio", "pinctrl-rp1"]), Some(2));
+        assert_eq!(header_chip_index(["brcmstb-gpio", "pinctrl-bcm2711"]), Some(1));
+        assert_eq!(header_chip_index(["pinctrl-bcm2835", "raspberrypi-exp-gpio"]), Some(0));
+        // labels match exactly
+        assert_eq!(header_chip_index(["pinctrl-rp1-aux", "PINCTRL-RP1", "gpio-mockup-A"]), None);
+        assert_eq!(header_chip_index([]), None);
+    }
+}
@@ -0,0 +1,791 @@
+use crate::output::json_string;
+use crate::{
+    range_to_timeout, Distance, HcSr04Error, LengthUnit, OutputFormat, RangeSensor, Reading, ReadingWriter,
+    MIN_MEASUREMENT_CYCLE,
+};
+use std::io::{self, BufRead, BufReader, Write};
+use std::iter::Peekable;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a [`DaemonClient`] waits for an answer when no timeout is given.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Readings queued for a subscriber before it starts missing some.
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+/// Owns one or more sensors, samples them in turn and serves their readings to
+/// other processes over a Unix domain socket, so that only one process needs
+/// to hold the GPIO lines. [`DaemonClient`] is the other end.
+///
+/// The protocol is line based, one request per line, one reply per request:
+///
+/// ```text
+/// SENSORS              OK <name> <name> ...
+/// INFO <name>          OK {"model":"HC-SR04","min_cm":2.00,"max_cm":400.00,"field_of_view":15,"update_rate":16.67}
+/// READ <name>          OK <record>    the latest reading
+/// NEXT <name>          OK <record>    the next reading, once it's taken
+/// SUBSCRIBE [<name>]   OK             then a <record> line per reading of that sensor, or of
+///                                     all sensors without a name, until the client hangs up
+/// anything else        ERR <message>
+/// ```
+///
+/// A `<record>` is a [`OutputFormat::JsonLines`] line of [`ReadingWriter`] in
+/// cm, e.g. `{"time":1760000000.123,"sensor":"front","status":"valid","distance":48.27,"unit":"cm","error":null}`.
+/// Commands are case-insensitive, names are not and can't contain whitespace.
+/// A subscribed connection takes no further requests.
+///
+/// Sensors are pinged one after another, each only once the echoes of the
+/// previous ping have died down, so neighbouring HC-SR04s don't hear each
+/// other's echoes. Subscribers are written to from their own threads; one that
+/// stops reading misses readings and is eventually dropped, without holding up
+/// the sensors or the other clients.
+pub struct Daemon {
+    sensors: Vec<Member>,
+    interval: Duration,
+}
+
+struct Member {
+    name: String,
+    sensor: Box<dyn RangeSensor + Send>,
+    /// how long this sensor's echoes take to die down
+    guard: Duration,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Daemon {
+            sensors: Vec::new(),
+            interval: Duration::from_millis(100),
+        }
+    }
+}
+
+struct Slot {
+    name: String,
+    /// reply to `INFO`
+    info: String,
+    latest: Option<String>,
+}
+
+struct Subscriber {
+    /// index into the slots, `None` for all of them
+    sensor: Option<usize>,
+    /// to the client's thread, which does the writing
+    records: SyncSender<String>,
+    /// answers a `NEXT`, dropped after one reading
+    once: bool,
+}
+
+/// What a client gets back for one request.
+enum Reply {
+    Line(String),
+    /// `NEXT`, answered with the next record
+    Next(Receiver<String>),
+    /// `SUBSCRIBE`, answered with every record from now on
+    Stream(Receiver<String>),
+}
+
+struct State {
+    slots: Vec<Slot>,
+    subscribers: Vec<Subscriber>,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+fn lock(shared: &Shared) -> MutexGuard<'_, State> {
+    shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The thread running [`sample`]. Dropping it stops the thread and waits for
+/// it, so the sensors are released whichever way [`Daemon::run`] returns.
+struct SampleThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SampleThread {
+    fn spawn(sensors: Vec<Member>, interval: Duration, shared: Shared) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("hcsr04-daemon".to_string())
+            .spawn(move || sample(sensors, interval, shared, &thread_stop))?;
+        Ok(SampleThread { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for SampleThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Daemon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sensor, addressed by clients as `name`, with the default guard of
+    /// [`MIN_MEASUREMENT_CYCLE`].
+    pub fn sensor<N: Into<String>, S: RangeSensor + Send + 'static>(self, name: N, sensor: S) -> Self {
+        self.sensor_with_guard(name, sensor, MIN_MEASUREMENT_CYCLE)
+    }
+
+    /// Adds a sensor whose echoes take `guard` to die down; no sensor is pinged
+    /// sooner than that after it.
+    pub fn sensor_with_guard<N: Into<String>, S: RangeSensor + Send + 'static>(
+        mut self,
+        name: N,
+        sensor: S,
+        guard: Duration,
+    ) -> Self {
+        self.sensors.push(Member { name: name.into(), sensor: Box::new(sensor), guard });
+        self
+    }
+
+    /// Time between the starts of two rounds over all sensors, 100 ms by
+    /// default. A round takes longer if the sensors and their guards need it.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Binds `path` and serves clients until accepting fails, then stops
+    /// sampling and drops the sensors before returning. A stale socket
+    /// file left by a previous run is replaced, one that's still being served
+    /// is not. The socket gets the process umask's permissions.
+    pub fn run<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if self.sensors.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "daemon has no sensors"))
+        }
+        for (i, Member { name, .. }) in self.sensors.iter().enumerate() {
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("sensor name {:?} is empty or contains whitespace", name),
+                ))
+            }
+            if self.sensors[..i].iter().any(|other| &other.name == name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("sensor name {:?} is used twice", name)))
+            }
+        }
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a daemon is already serving {}", path.display()),
+                ))
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+
+        let slots = self
+            .sensors
+            .iter()
+            .map(|Member { name, sensor, .. }| Slot {
+                name: name.clone(),
+                info: format!(
+                    "{{\"model\":{},\"min_cm\":{:.2},\"max_cm\":{:.2},\"field_of_view\":{},\"update_rate\":{:.2}}}",
+                    json_string(sensor.name()),
+                    sensor.min_distance().as_cm(),
+                    sensor.max_distance().as_cm(),
+                    sensor.field_of_view(),
+                    sensor.update_rate()
+                ),
+                latest: None,
+            })
+            .collect();
+        let shared = Arc::new(Mutex::new(State { slots, subscribers: Vec::new() }));
+
+        let _sampler = SampleThread::spawn(self.sensors, self.interval, shared.clone())?;
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let shared = shared.clone();
+            // a misbehaving client only loses its own connection
+            let _ = thread::Builder::new().name("hcsr04-client".to_string()).spawn(move || {
+                let _ = serve_client(stream, shared);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Sleeps until `until`, or until `stop` is set and the thread unparked.
+/// Returns `false` if stopped.
+fn pause(until: Instant, stop: &AtomicBool) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true
+        }
+        thread::park_timeout(until - now);
+    }
+}
+
+/// Pings every sensor in turn and hands the readings out, until `stop` is set.
+fn sample(mut sensors: Vec<Member>, interval: Duration, shared: Shared, stop: &AtomicBool) {
+    let writer = ReadingWriter::new(io::sink(), OutputFormat::JsonLines);
+    let mut next = Instant::now();
+    // when the echoes of the last ping are over
+    let mut quiet_at = Instant::now();
+    loop {
+        for (i, member) in sensors.iter_mut().enumerate() {
+            if !pause(quiet_at, stop) {
+                return
+            }
+            quiet_at = Instant::now() + member.guard;
+
+            let wall_clock = SystemTime::now();
+            let reading = member.sensor.read();
+            let record = writer.render(&member.name, wall_clock, &reading);
+
+            let mut state = lock(&shared);
+            state.subscribers.retain(|sub| {
+                if sub.sensor.is_some_and(|sensor| sensor != i) {
+                    return true
+                }
+                match sub.records.try_send(record.clone()) {
+                    Ok(()) => !sub.once,
+                    // a client that can't keep up misses readings, nobody waits for it
+                    Err(TrySendError::Full(_)) => true,
+                    Err(TrySendError::Disconnected(_)) => false,
+                }
+            });
+            state.slots[i].latest = Some(record);
+        }
+        // if this fell behind, don't try to catch up
+        next = (next + interval).max(Instant::now());
+        if !pause(next, stop) {
+            return
+        }
+    }
+}
+
+fn serve_client(stream: UnixStream, shared: Shared) -> io::Result<()> {
+    // a client that stops reading loses its connection
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut out = stream;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(())
+        }
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("").to_ascii_uppercase();
+        let name = words.next();
+        if words.next().is_some() {
+            writeln!(out, "ERR too many arguments")?;
+            continue;
+        }
+
+        let mut state = lock(&shared);
+        let slot = name.map(|name| state.slots.iter().position(|slot| slot.name == name));
+        let reply = match (command.as_str(), slot) {
+            (command, _) if !["SENSORS", "INFO", "READ", "NEXT", "SUBSCRIBE"].contains(&command) => {
+                Reply::Line(format!("ERR unknown command {:?}", command))
+            }
+            ("SENSORS", None) => {
+                let names: Vec<&str> = state.slots.iter().map(|slot| slot.name.as_str()).collect();
+                Reply::Line(format!("OK {}", names.join(" ")))
+            }
+            (_, Some(None)) => Reply::Line(format!("ERR unknown sensor {:?}", name.unwrap_or(""))),
+            ("INFO", Some(Some(i))) => Reply::Line(format!("OK {}", state.slots[i].info)),
+            ("READ", Some(Some(i))) => match &state.slots[i].latest {
+                Some(record) => Reply::Line(format!("OK {}", record)),
+                None => Reply::Line("ERR no reading yet".to_string()),
+            },
+            ("NEXT", Some(Some(i))) => {
+                let (records, next) = sync_channel(1);
+                state.subscribers.push(Subscriber { sensor: Some(i), records, once: true });
+                Reply::Next(next)
+            }
+            ("SUBSCRIBE", slot) => {
+                let (records, stream) = sync_channel(SUBSCRIBER_BACKLOG);
+                state.subscribers.push(Subscriber { sensor: slot.flatten(), records, once: false });
+                Reply::Stream(stream)
+            }
+            _ => Reply::Line(format!("ERR wrong number of arguments for {}", command)),
+        };
+        drop(state);
+
+        match reply {
+            Reply::Line(reply) => writeln!(out, "{}", reply)?,
+            Reply::Next(next) => match next.recv() {
+                Ok(record) => writeln!(out, "OK {}", record)?,
+                Err(_) => return Ok(())
+            },
+            Reply::Stream(stream) => {
+                writeln!(out, "OK")?;
+                for record in stream {
+                    writeln!(out, "{}", record)?;
+                }
+                return Ok(())
+            }
+        }
+    }
+}
+
+/// A reading served by a [`Daemon`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteReading {
+    pub sensor: String,
+    /// when the daemon took the reading
+    pub wall_clock: SystemTime,
+    pub reading: Reading,
+}
+
+/// Reads one sensor of a [`Daemon`], with the same measurement API as
+/// [`HcSr04`](crate::HcSr04).
+///
+/// `dist_*` and [`RangeSensor::measure`] wait for the next reading the daemon
+/// takes, so they are paced like the real sensor. A connection that times out
+/// or fails is dropped and opened again on the next call.
+#[derive(Debug)]
+pub struct DaemonClient {
+    path: PathBuf,
+    conn: Option<BufReader<UnixStream>>,
+    sensor: String,
+    model: String,
+    min: Distance,
+    max: Distance,
+    field_of_view: f64,
+    update_rate: f64,
+}
+
+impl DaemonClient {
+    /// Connects to the daemon at `path` and looks up `sensor`.
+    pub fn connect<P: AsRef<Path>>(path: P, sensor: &str) -> Result<Self, HcSr04Error> {
+        let mut client = DaemonClient {
+            path: path.as_ref().to_path_buf(),
+            conn: None,
+            sensor: sensor.to_string(),
+            model: String::new(),
+            min: Distance::default(),
+            max: Distance::default(),
+            field_of_view: 0.0,
+            update_rate: 0.0,
+        };
+
+        let info = client.request(&format!("INFO {}", sensor), None)?;
+        let fields = match parse_flat_json(&info) {
+            Some(fields) => fields,
+            None => return Err(bad_reply(&info))
+        };
+        let number = |key: &str| match field(&fields, key).and_then(|val| val.parse::<f64>().ok()) {
+            Some(val) => Ok(val),
+            None => Err(bad_reply(&info)),
+        };
+        client.model = field(&fields, "model").unwrap_or("unknown").to_string();
+        client.min = Distance::cm(number("min_cm")?);
+        client.max = Distance::cm(number("max_cm")?);
+        client.field_of_view = number("field_of_view")?;
+        client.update_rate = number("update_rate")?;
+        Ok(client)
+    }
+
+    /// Names of the sensors the daemon at `path` serves.
+    pub fn sensors<P: AsRef<Path>>(path: P) -> Result<Vec<String>, HcSr04Error> {
+        let mut conn = open(path.as_ref())?;
+        let names = exchange(&mut conn, "SENSORS", CLIENT_TIMEOUT)?;
+        Ok(names.split_whitespace().map(str::to_string).collect())
+    }
+
+    pub fn sensor(&self) -> &str {
+        &self.sensor
+    }
+
+    /// The reading the daemon took last, without waiting for a new one.
+    pub fn latest(&mut self) -> Result<RemoteReading, HcSr04Error> {
+        let record = self.request(&format!("READ {}", self.sensor), None)?;
+        parse_record(&record)
+    }
+
+    /// Waits for the next reading the daemon takes, up to `timeout` or 2 s.
+    pub fn next_reading(&mut self, timeout: Option<Duration>) -> Result<RemoteReading, HcSr04Error> {
+        let record = self.request(&format!("NEXT {}", self.sensor), timeout)?;
+        parse_record(&record)
+    }
+
+    /// Next reading in cm.
+    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.next_reading(timeout)?;
+        self.to_result(res.reading)
+    }
+
+    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(timeout).map(|res| res.to(LengthUnit::Mm))
+    }
+
+    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(timeout).map(|res| res.to(LengthUnit::Meter))
+    }
+
+    /// Turns the connection into a stream of every reading of this sensor.
+    pub fn subscribe(mut self) -> Result<Subscription, HcSr04Error> {
+        self.conn = None;
+        let mut conn = open(&self.path)?;
+        exchange(&mut conn, &format!("SUBSCRIBE {}", self.sensor), CLIENT_TIMEOUT)?;
+        // readings come at the daemon's pace, however slow
+        conn.get_ref().set_read_timeout(None).map_err(HcSr04Error::Daemon)?;
+        Ok(Subscription { conn })
+    }
+
+    /// The error a local driver would have returned for this reading.
+    fn to_result(&self, reading: Reading) -> Result<Distance, HcSr04Error> {
+        match reading {
+            Reading::Valid(distance) => Ok(distance),
+            Reading::TooNear(distance) | Reading::OutOfRange(Some(distance)) => Err(HcSr04Error::OutOfRange {
+                distance,
+                min: self.min,
+                max: Some(self.max),
+            }),
+            Reading::OutOfRange(None) => Err(HcSr04Error::NoEchoStart {
+                timeout: range_to_timeout(self.max).unwrap_or_default(),
+            }),
+            Reading::Fault(err) => Err(err),
+        }
+    }
+
+    /// Sends one request and returns the reply after `OK `, reconnecting
+    /// first if the last request failed.
+    fn request(&mut self, request: &str, timeout: Option<Duration>) -> Result<String, HcSr04Error> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => open(&self.path)?,
+        };
+        let reply = exchange(&mut conn, request, timeout.unwrap_or(CLIENT_TIMEOUT));
+        // after a timeout the reply may still arrive and would answer the wrong request
+        if !matches!(reply, Err(HcSr04Error::Daemon(_) | HcSr04Error::NoResponse { .. })) {
+            self.conn = Some(conn);
+        }
+        reply
+    }
+}
+
+impl RangeSensor for DaemonClient {
+    fn name(&self) -> &str {
+        &self.model
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(None)
+    }
+
+    fn min_distance(&self) -> Distance {
+        self.min
+    }
+
+    fn max_distance(&self) -> Distance {
+        self.max
+    }
+
+    fn field_of_view(&self) -> f64 {
+        self.field_of_view
+    }
+
+    fn update_rate(&self) -> f64 {
+        self.update_rate
+    }
+
+    /// The daemon's own outcome, rather than one rebuilt from an error.
+    fn read(&mut self) -> Reading {
+        match self.next_reading(None) {
+            Ok(res) => res.reading,
+            Err(err) => Reading::Fault(err),
+        }
+    }
+}
+
+/// Readings pushed by a [`Daemon`] after [`DaemonClient::subscribe`]. Ends when
+/// the daemon goes away.
+#[derive(Debug)]
+pub struct Subscription {
+    conn: BufReader<UnixStream>,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<RemoteReading, HcSr04Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.conn.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(parse_record(line.trim_end())),
+            Err(err) => Some(Err(HcSr04Error::Daemon(err))),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<UnixStream>, HcSr04Error> {
+    match UnixStream::connect(path) {
+        Ok(stream) => Ok(BufReader::new(stream)),
+        Err(source) => Err(HcSr04Error::DaemonConnect { path: path.to_path_buf(), source }),
+    }
+}
+
+fn exchange(conn: &mut BufReader<UnixStream>, request: &str, timeout: Duration) -> Result<String, HcSr04Error> {
+    let io = |conn: &mut BufReader<UnixStream>| -> io::Result<String> {
+        conn.get_ref().set_read_timeout(Some(timeout))?;
+        writeln!(conn.get_mut(), "{}", request)?;
+        let mut line = String::new();
+        if conn.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "daemon closed the connection"))
+        }
+        Ok(line)
+    };
+    let line = match io(conn) {
+        Ok(line) => line,
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            return Err(HcSr04Error::NoResponse { timeout })
+        }
+        Err(err) => return Err(HcSr04Error::Daemon(err))
+    };
+
+    let line = line.trim_end();
+    if line == "OK" {
+        return Ok(String::new())
+    }
+    if let Some(reply) = line.strip_prefix("OK ") {
+        return Ok(reply.to_string())
+    }
+    match line.strip_prefix("ERR ") {
+        Some(msg) => Err(HcSr04Error::Remote(msg.to_string())),
+        None => Err(bad_reply(line)),
+    }
+}
+
+fn bad_reply(reply: &str) -> HcSr04Error {
+    HcSr04Error::Daemon(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", reply)))
+}
+
+fn parse_record(record: &str) -> Result<RemoteReading, HcSr04Error> {
+    let fields = match parse_flat_json(record) {
+        Some(fields) => fields,
+        None => return Err(bad_reply(record))
+    };
+    let distance = field(&fields, "distance").and_then(|val| val.parse().ok()).map(Distance::cm);
+    let reading = match (field(&fields, "status"), distance) {
+        (Some("valid"), Some(distance)) => Reading::Valid(distance),
+        (Some("too_near"), Some(distance)) => Reading::TooNear(distance),
+        (Some("out_of_range"), distance) => Reading::OutOfRange(distance),
+        (Some("fault"), _) => Reading::Fault(HcSr04Error::Remote(field(&fields, "error").unwrap_or("fault").to_string())),
+        _ => return Err(bad_reply(record)),
+    };
+    let time = field(&fields, "time").and_then(|val| val.parse::<f64>().ok()).unwrap_or(0.0);
+
+    Ok(RemoteReading {
+        sensor: field(&fields, "sensor").unwrap_or_default().to_string(),
+        wall_clock: UNIX_EPOCH + Duration::try_from_secs_f64(time).unwrap_or_default(),
+        reading,
+    })
+}
+
+fn field<'a>(fields: &'a [(String, Option<String>)], key: &str) -> Option<&'a str> {
+    fields.iter().find(|(name, _)| name == key).and_then(|(_, val)| val.as_deref())
+}
+
+/// Fields of a flat JSON object like the ones the daemon sends. Strings come
+/// back unescaped, numbers as written, `null` as `None`.
+fn parse_flat_json(s: &str) -> Option<Vec<(String, Option<String>)>> {
+    let mut chars = s.trim().chars().peekable();
+    let skip_ws = |chars: &mut Peekable<Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+
+    if chars.next()? != '{' {
+        return None
+    }
+    let mut fields = Vec::new();
+    skip_ws(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Some(fields)
+    }
+    loop {
+        skip_ws(&mut chars);
+        let key = parse_json_string(&mut chars)?;
+        skip_ws(&mut chars);
+        if chars.next()? != ':' {
+            return None
+        }
+        skip_ws(&mut chars);
+        let value = match chars.peek()? {
+            '"' => Some(parse_json_string(&mut chars)?),
+            _ => {
+                let mut literal = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',' && *c != '}' && !c.is_whitespace()) {
+                    literal.push(c);
+                }
+                match literal.as_str() {
+                    "null" => None,
+                    _ => Some(literal),
+                }
+            }
+        };
+        fields.push((key, value));
+        skip_ws(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return Some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_json_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None
+    }
+    let mut out = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(out),
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'u' => {
+                    let hex: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                    out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?).unwrap_or('\u{fffd}'));
+                }
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HcSr04, MockBackend, MockEcho};
+    use std::iter::repeat_n;
+    use std::thread::sleep;
+
+    fn mock(cm: f64) -> HcSr04<MockBackend> {
+        let script = repeat_n(MockEcho::from_distance(Distance::cm(cm)), 1000);
+        HcSr04::with_backend(MockBackend::with_script(script), Distance::cm(2.0))
+    }
+
+    /// Starts a daemon on a fresh socket and waits until it answers.
+    fn start(daemon: Daemon, test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hcsr04-{}-{}.sock", test, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = path.clone();
+        thread::spawn(move || daemon.run(socket));
+
+        let started = Instant::now();
+        while DaemonClient::sensors(&path).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(5), "daemon did not come up");
+            sleep(Duration::from_millis(10));
+        }
+        path
+    }
+
+    #[test]
+    fn serves_readings() {
+        let path = start(Daemon::new().sensor("front", mock(120.0)).sensor("back", mock(30.0)), "serve");
+        assert_eq!(DaemonClient::sensors(&path).unwrap(), ["front", "back"]);
+
+        let mut front = DaemonClient::connect(&path, "front").unwrap();
+        assert_eq!(front.name(), "HC-SR04");
+        assert_eq!(front.min_distance(), Distance::cm(2.0));
+        let distance = front.dist_cm(None).unwrap();
+        assert!((distance.as_cm() - 120.0).abs() < 0.01, "{:?}", distance);
+        assert!(front.latest().unwrap().reading.is_valid());
+        assert!(front.read().is_valid());
+
+        let back = DaemonClient::connect(&path, "back").unwrap();
+        for res in back.subscribe().unwrap().take(3) {
+            let res = res.unwrap();
+            assert_eq!(res.sensor, "back");
+            let distance = res.reading.distance().unwrap();
+            assert!((distance.as_cm() - 30.0).abs() < 0.01, "{:?}", distance);
+        }
+
+        assert!(matches!(DaemonClient::connect(&path, "side"), Err(HcSr04Error::Remote(_))));
+    }
+
+    #[test]
+    fn waits_for_echoes_to_die_down() {
+        let path = start(
+            Daemon::new().sensor("left", mock(50.0)).sensor("right", mock(50.0)).interval(Duration::ZERO),
+            "guard",
+        );
+        let mut conn = open(&path).unwrap();
+        exchange(&mut conn, "SUBSCRIBE", CLIENT_TIMEOUT).unwrap();
+
+        let mut line = String::new();
+        let mut times = Vec::new();
+        for _ in 0..5 {
+            line.clear();
+            conn.read_line(&mut line).unwrap();
+            let res = parse_record(line.trim_end()).unwrap();
+            times.push(res.wall_clock);
+        }
+        for pair in times.windows(2) {
+            // records carry milliseconds
+            let gap = pair[1].duration_since(pair[0]).unwrap();
+            assert!(gap + Duration::from_millis(1) >= MIN_MEASUREMENT_CYCLE, "pings {:?} apart", gap);
+        }
+    }
+
+    #[test]
+    fn stalled_subscriber_holds_nobody_up() {
+        let daemon = Daemon::new().sensor_with_guard("front", mock(80.0), Duration::from_millis(5)).interval(Duration::ZERO);
+        let path = start(daemon, "stall");
+
+        // subscribes and never reads
+        let mut stalled = UnixStream::connect(&path).unwrap();
+        writeln!(stalled, "SUBSCRIBE").unwrap();
+
+        let mut front = DaemonClient::connect(&path, "front").unwrap();
+        let started = Instant::now();
+        for _ in 0..(2 * SUBSCRIBER_BACKLOG) {
+            front.next_reading(None).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stopping_joins_the_sampler() {
+        let slot = Slot { name: "front".to_string(), info: String::new(), latest: None };
+        let shared = Arc::new(Mutex::new(State { slots: vec![slot], subscribers: Vec::new() }));
+        let member = Member { name: "front".to_string(), sensor: Box::new(mock(80.0)), guard: Duration::ZERO };
+        let sampler = SampleThread::spawn(vec![member], Duration::from_secs(3600), shared.clone()).unwrap();
+
+        let started = Instant::now();
+        while lock(&shared).slots[0].latest.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5), "no reading taken");
+            sleep(Duration::from_millis(1));
+        }
+        // wakes the thread from its hour-long pause
+        drop(sampler);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // the thread and the sensors it owned are gone
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+}
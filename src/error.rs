@@ -0,0 +1,294 @@
+use crate::{Distance, LengthUnit};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum HcSr04Error {
+    /// the GPIO chip could not be opened
+    ChipOpen {
+        path: PathBuf,
+        source: gpio_cdev::Error,
+    },
+    /// no GPIO chip matched, the string says what was looked for
+    ChipNotFound(String),
+    /// a line could not be requested, e.g. `EBUSY` when another process holds it
+    LineRequest {
+        offset: u32,
+        errno: Option<i32>,
+        source: gpio_cdev::Error,
+    },
+    /// reading or writing an already requested line failed
+    Gpio(gpio_cdev::Error),
+    /// `poll` on the echo event fd failed
+    Poll(io::Error),
+    /// echo never went high
+    NoEchoStart { timeout: Duration },
+    /// echo went high but never came back down
+    EchoNeverEnded { timeout: Duration },
+    /// the reading is outside the configured valid range
+    OutOfRange {
+        distance: Distance,
+        min: Distance,
+        max: Option<Distance>,
+    },
+    /// rejected by [`HcSr04Builder::build`](crate::HcSr04Builder::build)
+    InvalidConfig(String),
+    /// the serial port of a UART sensor could not be opened or set up
+    SerialOpen {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// reading or writing an already open serial port failed
+    Serial(io::Error),
+    /// a UART sensor or the daemon sent nothing usable in time
+    NoResponse { timeout: Duration },
+    /// a UART frame's checksum byte didn't match its contents
+    Checksum { expected: u8, actual: u8 },
+    /// the daemon socket could not be connected to
+    DaemonConnect {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// talking to the daemon over an open connection failed
+    Daemon(io::Error),
+    /// the daemon answered with an error, or its sensor failed
+    Remote(String),
+}
+
+/// The errno behind a gpio-cdev error. gpio-cdev 0.6 keeps its `ErrorKind`
+/// private, so this goes through the source: a nix `Errno` for failed ioctls
+/// and event reads, an `io::Error` for the rest. An out of range offset has
+/// none.
+fn gpio_errno(err: &gpio_cdev::Error) -> Option<i32> {
+    let cause = std::error::Error::source(err)?;
+    let errno = match cause.downcast_ref::<nix::errno::Errno>() {
+        Some(errno) => *errno as i32,
+        None => cause.downcast_ref::<io::Error>()?.raw_os_error()?,
+    };
+    (errno != 0).then_some(errno)
+}
+
+impl HcSr04Error {
+    /// Wraps a failed line lookup or request, with the errno of the failed
+    /// ioctl or I/O call if there was one.
+    pub(crate) fn line_request(offset: u32, source: gpio_cdev::Error) -> Self {
+        HcSr04Error::LineRequest { offset, errno: gpio_errno(&source), source }
+    }
+
+    /// The variant as a snake_case word, e.g. `"no_echo_start"`, for labels and
+    /// logs. Matches the `kind` tag of the serialized form.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HcSr04Error::ChipOpen { .. } => "chip_open",
+            HcSr04Error::ChipNotFound(_) => "chip_not_found",
+            HcSr04Error::LineRequest { .. } => "line_request",
+            HcSr04Error::Gpio(_) => "gpio",
+            HcSr04Error::Poll(_) => "poll",
+            HcSr04Error::NoEchoStart { .. } => "no_echo_start",
+            HcSr04Error::EchoNeverEnded { .. } => "echo_never_ended",
+            HcSr04Error::OutOfRange { .. } => "out_of_range",
+            HcSr04Error::InvalidConfig(_) => "invalid_config",
+            HcSr04Error::SerialOpen { .. } => "serial_open",
+            HcSr04Error::Serial(_) => "serial",
+            HcSr04Error::NoResponse { .. } => "no_response",
+            HcSr04Error::Checksum { .. } => "checksum",
+            HcSr04Error::DaemonConnect { .. } => "daemon_connect",
+            HcSr04Error::Daemon(_) => "daemon",
+            HcSr04Error::Remote(_) => "remote",
+        }
+    }
+
+    /// Kernel errno behind the failure, if there is one.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            HcSr04Error::LineRequest { errno, .. } => *errno,
+            HcSr04Error::Poll(err) => err.raw_os_error(),
+            HcSr04Error::SerialOpen { source, .. } => source.raw_os_error(),
+            HcSr04Error::Serial(err) => err.raw_os_error(),
+            HcSr04Error::DaemonConnect { source, .. } => source.raw_os_error(),
+            HcSr04Error::Daemon(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HcSr04Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HcSr04Error::ChipOpen { path, .. } => write!(f, "failed to open GPIO chip {}", path.display()),
+            HcSr04Error::ChipNotFound(wanted) => write!(f, "no GPIO chip found matching {}", wanted),
+            HcSr04Error::LineRequest { offset, errno: Some(errno), .. } => write!(
+                f,
+                "failed to request GPIO line {}: {}",
+                offset,
+                io::Error::from_raw_os_error(*errno)
+            ),
+            HcSr04Error::LineRequest { offset, errno: None, .. } => write!(f, "failed to request GPIO line {}", offset),
+            HcSr04Error::Gpio(_) => write!(f, "GPIO line access failed"),
+            HcSr04Error::Poll(err) => write!(f, "poll on echo line failed: {}", err),
+            HcSr04Error::NoEchoStart { timeout } => write!(f, "no echo within {:?}", timeout),
+            HcSr04Error::EchoNeverEnded { timeout } => write!(f, "echo did not end within {:?}", timeout),
+            HcSr04Error::OutOfRange { distance, max: Some(max), .. } if distance > max => write!(
+                f,
+                "reading of {:.2} is above the {:.2} maximum",
+                distance.to(LengthUnit::Cm), max.to(LengthUnit::Cm)
+            ),
+            HcSr04Error::OutOfRange { distance, min, .. } => write!(
+                f,
+                "reading of {:.2} is below the {:.2} threshold",
+                distance.to(LengthUnit::Cm), min.to(LengthUnit::Cm)
+            ),
+            HcSr04Error::InvalidConfig(msg) => write!(f, "invalid configuration: {}", msg),
+            HcSr04Error::SerialOpen { path, source } => write!(f, "failed to open serial port {}: {}", path.display(), source),
+            HcSr04Error::Serial(err) => write!(f, "serial port access failed: {}", err),
+            HcSr04Error::NoResponse { timeout } => write!(f, "no reply from the sensor within {:?}", timeout),
+            HcSr04Error::Checksum { expected, actual } => write!(
+                f,
+                "frame checksum mismatch, expected {:#04x} but got {:#04x}",
+                expected, actual
+            ),
+            HcSr04Error::DaemonConnect { path, source } => write!(f, "failed to connect to daemon at {}: {}", path.display(), source),
+            HcSr04Error::Daemon(err) => write!(f, "daemon connection failed: {}", err),
+            HcSr04Error::Remote(msg) => write!(f, "daemon reported: {}", msg),
+        }
+    }
+}
+
+impl Error for HcSr04Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HcSr04Error::ChipOpen { source, .. } => Some(source),
+            HcSr04Error::LineRequest { source, .. } => Some(source),
+            HcSr04Error::Gpio(err) => Some(err),
+            HcSr04Error::Poll(err) => Some(err),
+            HcSr04Error::SerialOpen { source, .. } => Some(source),
+            HcSr04Error::Serial(err) => Some(err),
+            HcSr04Error::DaemonConnect { source, .. } => Some(source),
+            HcSr04Error::Daemon(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<gpio_cdev::Error> for HcSr04Error {
+    fn from(err: gpio_cdev::Error) -> Self {
+        HcSr04Error::Gpio(err)
+    }
+}
+
+/// Serialized form of [`HcSr04Error`]. Source errors can't be serialized, so
+/// they travel as their errno and message and come back as plain I/O errors.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ErrorRepr {
+    ChipOpen { path: PathBuf, message: String },
+    ChipNotFound { wanted: String },
+    LineRequest { offset: u32, errno: Option<i32>, message: String },
+    Gpio { message: String },
+    Poll { errno: Option<i32>, message: String },
+    NoEchoStart { timeout: Duration },
+    EchoNeverEnded { timeout: Duration },
+    OutOfRange { distance: Distance, min: Distance, max: Option<Distance> },
+    InvalidConfig { message: String },
+    SerialOpen { path: PathBuf, errno: Option<i32>, message: String },
+    Serial { errno: Option<i32>, message: String },
+    NoResponse { timeout: Duration },
+    Checksum { expected: u8, actual: u8 },
+    DaemonConnect { path: PathBuf, errno: Option<i32>, message: String },
+    Daemon { errno: Option<i32>, message: String },
+    Remote { message: String },
+}
+
+#[cfg(feature = "serde")]
+fn io_error(errno: Option<i32>, message: String) -> io::Error {
+    match errno {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => io::Error::other(message),
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for HcSr04Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            HcSr04Error::ChipOpen { path, source } => ErrorRepr::ChipOpen { path: path.clone(), message: source.to_string() },
+            HcSr04Error::ChipNotFound(wanted) => ErrorRepr::ChipNotFound { wanted: wanted.clone() },
+            HcSr04Error::LineRequest { offset, errno, source } => ErrorRepr::LineRequest {
+                offset: *offset,
+                errno: *errno,
+                message: source.to_string(),
+            },
+            HcSr04Error::Gpio(err) => ErrorRepr::Gpio { message: err.to_string() },
+            HcSr04Error::Poll(err) => ErrorRepr::Poll { errno: err.raw_os_error(), message: err.to_string() },
+            HcSr04Error::NoEchoStart { timeout } => ErrorRepr::NoEchoStart { timeout: *timeout },
+            HcSr04Error::EchoNeverEnded { timeout } => ErrorRepr::EchoNeverEnded { timeout: *timeout },
+            HcSr04Error::OutOfRange { distance, min, max } => ErrorRepr::OutOfRange { distance: *distance, min: *min, max: *max },
+            HcSr04Error::InvalidConfig(message) => ErrorRepr::InvalidConfig { message: message.clone() },
+            HcSr04Error::SerialOpen { path, source } => ErrorRepr::SerialOpen {
+                path: path.clone(),
+                errno: source.raw_os_error(),
+                message: source.to_string(),
+            },
+            HcSr04Error::Serial(err) => ErrorRepr::Serial { errno: err.raw_os_error(), message: err.to_string() },
+            HcSr04Error::NoResponse { timeout } => ErrorRepr::NoResponse { timeout: *timeout },
+            HcSr04Error::Checksum { expected, actual } => ErrorRepr::Checksum { expected: *expected, actual: *actual },
+            HcSr04Error::DaemonConnect { path, source } => ErrorRepr::DaemonConnect {
+                path: path.clone(),
+                errno: source.raw_os_error(),
+                message: source.to_string(),
+            },
+            HcSr04Error::Daemon(err) => ErrorRepr::Daemon { errno: err.raw_os_error(), message: err.to_string() },
+            HcSr04Error::Remote(message) => ErrorRepr::Remote { message: message.clone() },
+        };
+        serde::Serialize::serialize(&repr, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HcSr04Error {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let gpio_error = |message: String| gpio_cdev::Error::from(io::Error::other(message));
+
+        Ok(match ErrorRepr::deserialize(deserializer)? {
+            ErrorRepr::ChipOpen { path, message } => HcSr04Error::ChipOpen { path, source: gpio_error(message) },
+            ErrorRepr::ChipNotFound { wanted } => HcSr04Error::ChipNotFound(wanted),
+            ErrorRepr::LineRequest { offset, errno, message } => HcSr04Error::LineRequest {
+                offset,
+                errno,
+                source: gpio_error(message),
+            },
+            ErrorRepr::Gpio { message } => HcSr04Error::Gpio(gpio_error(message)),
+            ErrorRepr::Poll { errno, message } => HcSr04Error::Poll(io_error(errno, message)),
+            ErrorRepr::NoEchoStart { timeout } => HcSr04Error::NoEchoStart { timeout },
+            ErrorRepr::EchoNeverEnded { timeout } => HcSr04Error::EchoNeverEnded { timeout },
+            ErrorRepr::OutOfRange { distance, min, max } => HcSr04Error::OutOfRange { distance, min, max },
+            ErrorRepr::InvalidConfig { message } => HcSr04Error::InvalidConfig(message),
+            ErrorRepr::SerialOpen { path, errno, message } => HcSr04Error::SerialOpen { path, source: io_error(errno, message) },
+            ErrorRepr::Serial { errno, message } => HcSr04Error::Serial(io_error(errno, message)),
+            ErrorRepr::NoResponse { timeout } => HcSr04Error::NoResponse { timeout },
+            ErrorRepr::Checksum { expected, actual } => HcSr04Error::Checksum { expected, actual },
+            ErrorRepr::DaemonConnect { path, errno, message } => HcSr04Error::DaemonConnect { path, source: io_error(errno, message) },
+            ErrorRepr::Daemon { errno, message } => HcSr04Error::Daemon(io_error(errno, message)),
+            ErrorRepr::Remote { message } => HcSr04Error::Remote(message),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_request_errno_comes_from_the_source() {
+        let busy = gpio_cdev::Error::from(io::Error::from_raw_os_error(libc::EBUSY));
+        let err = HcSr04Error::line_request(23, busy);
+        assert!(matches!(err, HcSr04Error::LineRequest { offset: 23, errno: Some(libc::EBUSY), .. }));
+
+        let plain = gpio_cdev::Error::from(io::Error::other("no errno"));
+        assert!(matches!(HcSr04Error::line_request(23, plain), HcSr04Error::LineRequest { errno: None, .. }));
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{median, Distance, HcSr04Error};
+use std::collections::VecDeque;
+
+/// Turns a stream of readings into a smoothed estimate, one sample at a time.
+///
+/// `None` is a missed sample (timeout, out of range). Filters hold or decay their
+/// estimate instead of treating it as a zero.
+pub trait Filter {
+    /// Feeds one reading and returns the new estimate.
+    fn update(&mut self, reading: Option<f64>) -> Option<f64>;
+
+    /// Current estimate, `None` until the first reading.
+    fn estimate(&self) -> Option<f64>;
+
+    /// Forgets everything seen so far.
+    fn reset(&mut self);
+
+    /// Feeds this filter's output into `next`.
+    fn then<F: Filter>(self, next: F) -> Chain<Self, F>
+    where
+        Self: Sized,
+    {
+        Chain { first: self, second: next }
+    }
+}
+
+impl<F: Filter + ?Sized> Filter for Box<F> {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        (**self).update(reading)
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        (**self).estimate()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+/// Mean of the last `window` readings.
+#[derive(Debug, Clone)]
+pub struct MovingAverage {
+    window: usize,
+    samples: VecDeque<f64>,
+}
+
+impl MovingAverage {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            samples: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Readings currently in the window, oldest first.
+    pub fn samples(&self) -> &VecDeque<f64> {
+        &self.samples
+    }
+}
+
+impl Filter for MovingAverage {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        if let Some(val) = reading {
+            if self.samples.len() == self.window {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(val);
+        }
+        self.estimate()
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None
+        }
+        Some(self.samples.iter().sum::<f64>() / self.samples.len() as f64)
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Median of the last `window` readings.
+#[derive(Debug, Clone)]
+pub struct MedianWindow {
+    window: usize,
+    samples: VecDeque<f64>,
+}
+
+impl MedianWindow {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            samples: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Readings currently in the window, oldest first.
+    pub fn samples(&self) -> &VecDeque<f64> {
+        &self.samples
+    }
+}
+
+impl Filter for MedianWindow {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        if let Some(val) = reading {
+            if self.samples.len() == self.window {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(val);
+        }
+        self.estimate()
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        let samples: Vec<f64> = self.samples.iter().copied().collect();
+        median(&samples)
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Exponential moving average, `alpha` is the weight of the newest reading.
+#[derive(Debug, Clone)]
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(0.0, 1.0),
+            value: None,
+        }
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+}
+
+impl Filter for Ema {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        if let Some(val) = reading {
+            self.value = Some(match self.value {
+                Some(prev) => prev + self.alpha * (val - prev),
+                None => val,
+            });
+        }
+        self.value
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+/// Scalar Kalman filter for a target that is assumed to hold still between
+/// samples, with `process_noise` variance of drift per sample.
+///
+/// Missed samples only run the predict step, so the uncertainty grows and the
+/// next reading is trusted more.
+#[derive(Debug, Clone)]
+pub struct Kalman {
+    process_noise: f64,
+    measurement_noise: f64,
+    value: Option<f64>,
+    variance: f64,
+    gain: f64,
+}
+
+impl Kalman {
+    /// Noises are variances in the unit squared of the readings, e.g. cm².
+    pub fn new(process_noise: f64, measurement_noise: f64) -> Self {
+        Self {
+            process_noise,
+            measurement_noise,
+            value: None,
+            variance: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    /// Variance of the current estimate.
+    pub fn variance(&self) -> f64 {
+        self.variance
+    }
+
+    /// Kalman gain used for the last reading.
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    pub fn process_noise(&self) -> f64 {
+        self.process_noise
+    }
+
+    pub fn measurement_noise(&self) -> f64 {
+        self.measurement_noise
+    }
+}
+
+impl Filter for Kalman {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        let value = match (self.value, reading) {
+            (None, None) => return None,
+            (None, Some(val)) => {
+                // first reading, as good as the sensor
+                self.variance = self.measurement_noise;
+                self.gain = 1.0;
+                val
+            }
+            (Some(prev), reading) => {
+                self.variance += self.process_noise;
+                match reading {
+                    Some(val) => {
+                        self.gain = self.variance / (self.variance + self.measurement_noise);
+                        self.variance *= 1.0 - self.gain;
+                        prev + self.gain * (val - prev)
+                    }
+                    None => prev,
+                }
+            }
+        };
+        self.value = Some(value);
+        self.value
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.value = None;
+        self.variance = 0.0;
+        self.gain = 0.0;
+    }
+}
+
+/// Two filters in series, see [`Filter::then`].
+#[derive(Debug, Clone)]
+pub struct Chain<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: Filter, B: Filter> Filter for Chain<A, B> {
+    fn update(&mut self, reading: Option<f64>) -> Option<f64> {
+        let intermediate = self.first.update(reading);
+        // a missed sample stays missed rather than repeating the first estimate
+        self.second.update(reading.and(intermediate))
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        self.second.estimate()
+    }
+
+    fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+    }
+}
+
+/// One step of a [`Filtered`] source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FilteredReading {
+    /// what the source returned, `None` if the sample was missed
+    pub raw: Option<Distance>,
+    pub estimate: Option<Distance>,
+}
+
+/// Runs every reading of a source through a filter, in cm.
+///
+/// Timeouts and out-of-range readings are fed to the filter as missed samples;
+/// any other error is passed on.
+pub struct Filtered<S, F> {
+    source: S,
+    filter: F,
+}
+
+impl<S, F> Filtered<S, F>
+where
+    S: FnMut() -> Result<Distance, HcSr04Error>,
+    F: Filter,
+{
+    /// e.g. `Filtered::new(|| hcsr04.dist_cm(None), Ema::new(0.3))`
+    pub fn new(source: S, filter: F) -> Self {
+        Self { source, filter }
+    }
+
+    pub fn read(&mut self) -> Result<FilteredReading, HcSr04Error> {
+        let raw = match (self.source)() {
+            Ok(dist) => Some(dist),
+            Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. } | HcSr04Error::OutOfRange { .. }) => None,
+            Err(err) => return Err(err),
+        };
+
+        let estimate = self.filter.update(raw.as_ref().map(Distance::as_cm));
+        Ok(FilteredReading {
+            raw,
+            estimate: estimate.map(Distance::cm),
+        })
+    }
+
+    pub fn filter(&self) -> &F {
+        &self.filter
+    }
+
+    pub fn filter_mut(&mut self) -> &mut F {
+        &mut self.filter
+    }
+
+    pub fn into_parts(self) -> (S, F) {
+        (self.source, self.filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn run<F: Filter>(filter: &mut F, readings: &[Option<f64>]) -> Vec<Option<f64>> {
+        readings.iter().map(|reading| filter.update(*reading)).collect()
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("an estimate");
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut filter = MovingAverage::new(3);
+        assert_eq!(filter.estimate(), None);
+        assert_eq!(run(&mut filter, &[Some(3.0), Some(6.0), Some(9.0), Some(12.0)]), [Some(3.0), Some(4.5), Some(6.0), Some(9.0)]);
+        assert_eq!(filter.samples(), &[6.0, 9.0, 12.0]);
+
+        // a missed sample holds the estimate
+        assert_eq!(filter.update(None), Some(9.0));
+        filter.reset();
+        assert_eq!(filter.update(None), None);
+    }
+
+    #[test]
+    fn median_window() {
+        let mut filter = MedianWindow::new(3);
+        assert_eq!(run(&mut filter, &[Some(10.0), Some(100.0), Some(12.0), Some(11.0)]), [Some(10.0), Some(55.0), Some(12.0), Some(12.0)]);
+        assert_eq!(filter.update(None), Some(12.0));
+        assert_eq!(filter.samples().len(), 3);
+    }
+
+    #[test]
+    fn ema() {
+        let mut filter = Ema::new(0.25);
+        assert_eq!(run(&mut filter, &[Some(8.0), Some(16.0), None, Some(0.0)]), [Some(8.0), Some(10.0), Some(10.0), Some(7.5)]);
+        assert_eq!(Ema::new(3.0).alpha(), 1.0);
+    }
+
+    #[test]
+    fn kalman() {
+        let mut filter = Kalman::new(1.0, 4.0);
+        assert_eq!(filter.update(None), None);
+
+        assert_eq!(filter.update(Some(10.0)), Some(10.0));
+        assert_eq!(filter.variance(), 4.0);
+
+        // prior variance 5, gain 5/9
+        assert_close(filter.update(Some(20.0)), 10.0 + 50.0 / 9.0);
+        assert_close(Some(filter.gain()), 5.0 / 9.0);
+        assert_close(Some(filter.variance()), 20.0 / 9.0);
+
+        // a miss holds the estimate but grows the uncertainty
+        assert_close(filter.update(None), 10.0 + 50.0 / 9.0);
+        assert_close(Some(filter.variance()), 29.0 / 9.0);
+
+        let mut steady = filter.clone();
+        steady.variance = 20.0 / 9.0;
+        filter.update(Some(15.0));
+        steady.update(Some(15.0));
+        assert!(filter.gain() > steady.gain());
+    }
+
+    #[test]
+    fn chain_runs_stages_in_order() {
+        let readings = [Some(0.0), Some(100.0), Some(0.0)];
+        let mut median_first = MedianWindow::new(3).then(Ema::new(0.5));
+        assert_eq!(run(&mut median_first, &readings), [Some(0.0), Some(25.0), Some(12.5)]);
+        let mut ema_first = Ema::new(0.5).then(MedianWindow::new(3));
+        assert_eq!(run(&mut ema_first, &readings), [Some(0.0), Some(25.0), Some(25.0)]);
+
+        // the miss reaches the second stage as a miss
+        assert_eq!(median_first.update(None), Some(12.5));
+        assert_eq!(median_first.second.estimate(), Some(12.5));
+        median_first.reset();
+        assert_eq!(median_first.first.estimate(), None);
+        assert_eq!(median_first.estimate(), None);
+    }
+
+    #[test]
+    fn filtered_misses_and_errors() {
+        let mut results = vec![
+            Ok(Distance::cm(40.0)),
+            Err(HcSr04Error::NoEchoStart { timeout: Duration::from_millis(10) }),
+            Err(HcSr04Error::EchoNeverEnded { timeout: Duration::from_millis(10) }),
+            Err(HcSr04Error::OutOfRange { distance: Distance::cm(1.0), min: Distance::cm(2.0), max: None }),
+            Err(HcSr04Error::InvalidConfig("gone".to_string())),
+            Ok(Distance::cm(60.0)),
+        ]
+        .into_iter();
+        let mut filtered = Filtered::new(move || results.next().unwrap(), Ema::new(0.5));
+
+        assert_eq!(filtered.read().unwrap(), FilteredReading { raw: Some(Distance::cm(40.0)), estimate: Some(Distance::cm(40.0)) });
+        for _ in 0..3 {
+            assert_eq!(filtered.read().unwrap(), FilteredReading { raw: None, estimate: Some(Distance::cm(40.0)) });
+        }
+        assert!(matches!(filtered.read(), Err(HcSr04Error::InvalidConfig(_))));
+        assert_eq!(filtered.read().unwrap().estimate, Some(Distance::cm(50.0)));
+    }
+}
@@ -1,22 +1,70 @@
-use gpio_cdev::{Chip, EventRequestFlags, EventType, Line, LineHandle, LineRequestFlags};
-use std::{thread::sleep, time::*};
-use std::os::unix::io::AsRawFd;
+use gpio_cdev::Chip;
+use std::path::Path;
+use std::time::*;
+
+pub mod error;
+pub use error::HcSr04Error;
+pub mod array;
+pub use array::{Frame, FrameReading, Schedule, SensorArray};
+pub mod backend;
+pub use backend::{
+    Bias, CdevBackend, CdevMultiBackend, EchoBackend, Edge, EdgeEvent, MockBackend, MockEcho, MockMultiBackend,
+    MultiEchoBackend, SinglePinBackend,
+};
+pub mod burst;
+pub use burst::{aggregate, median, Aggregate, Aggregated, BurstConfig, BurstResult};
+pub mod builder;
+pub use builder::{HcSr04Builder, HcSr04Config};
+use builder::{check_range, Settings};
+pub mod chip;
+pub use chip::{find_chip_by_label, find_header_chip, gpio_chips, HEADER_CHIP_LABELS};
+
+#[cfg(feature = "tokio")]
+pub mod async_tokio;
+#[cfg(feature = "tokio")]
+pub use async_tokio::AsyncHcSr04;
+pub mod daemon;
+pub use daemon::{Daemon, DaemonClient, RemoteReading, Subscription};
+pub mod filter;
+pub use filter::{Chain, Ema, Filter, Filtered, FilteredReading, Kalman, MedianWindow, MovingAverage};
+pub mod measurement;
+pub use measurement::{Measurement, MeasurementFlags};
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "prometheus")]
+pub use metrics::{Metrics, MetricsServer};
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub use mqtt::{MqttPublisher, MqttPublisherBuilder, QoS};
+pub mod output;
+pub use output::{OutputFormat, ReadingWriter};
+pub mod reading;
+pub use reading::Reading;
+pub mod sampler;
+pub use sampler::{Delivery, Sample, Sampler, MIN_MEASUREMENT_CYCLE};
+pub mod sensor;
+pub use sensor::RangeSensor;
+pub mod shared;
+pub use shared::SharedTrigger;
+pub mod sound;
+pub use sound::{Environment, SpeedOfSound};
+pub mod uart;
+pub use uart::{open_serial, FrameModel, FrameOutput, FrameSensor, Us100};
+pub mod units;
+pub use units::{Distance, LengthUnit, ParseQuantityError, SpeedUnit, Velocity};
 
 const DEFAULT_TIMEOUT_MICROSECS: u64 = 8746;
 
-#[derive(Debug)]
-pub enum HcSr04Error {
-    Io,
-    Init,
-    LineEventHandleRequest,
-    PollFd
-}
-
+/// Superseded by [`Distance`], which converts to and from it.
+#[deprecated(note = "use `Distance`")]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DistanceUnit {
     Mm(f64),
     Cm(f64),
     Meter(f64),
 }
+#[allow(deprecated)]
 impl DistanceUnit {
     pub fn write_val(&mut self, new_val: f64) {
         match self {
@@ -35,10 +83,36 @@ impl DistanceUnit {
     }
 }
 
+#[allow(deprecated)]
+impl From<DistanceUnit> for Distance {
+    fn from(dist: DistanceUnit) -> Self {
+        match dist {
+            DistanceUnit::Mm(val) => Distance::mm(val),
+            DistanceUnit::Cm(val) => Distance::cm(val),
+            DistanceUnit::Meter(val) => Distance::meters(val),
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl From<Distance> for DistanceUnit {
+    fn from(dist: Distance) -> Self {
+        match dist.unit() {
+            LengthUnit::Mm => DistanceUnit::Mm(dist.value()),
+            LengthUnit::Cm => DistanceUnit::Cm(dist.value()),
+            LengthUnit::Meter => DistanceUnit::Meter(dist.value()),
+        }
+    }
+}
+
+/// Superseded by [`Velocity`], which converts to and from it.
+#[deprecated(note = "use `Velocity`")]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VelocityUnit {
     MetersPerSecs(f64),
     CentimeterPerSecs(f64),
 }
+#[allow(deprecated)]
 impl VelocityUnit {
     pub fn to_val(&self) -> f64 {
         match self {
@@ -48,171 +122,309 @@ impl VelocityUnit {
     }
 }
 
-const SPEED_OF_SOUND: VelocityUnit = VelocityUnit::MetersPerSecs(343.0);
+#[allow(deprecated)]
+impl From<VelocityUnit> for Velocity {
+    fn from(vel: VelocityUnit) -> Self {
+        match vel {
+            VelocityUnit::MetersPerSecs(val) => Velocity::meters_per_sec(val),
+            VelocityUnit::CentimeterPerSecs(val) => Velocity::cm_per_sec(val),
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl From<Velocity> for VelocityUnit {
+    fn from(vel: Velocity) -> Self {
+        match vel.unit() {
+            SpeedUnit::CmPerSec => VelocityUnit::CentimeterPerSecs(vel.value()),
+            _ => VelocityUnit::MetersPerSecs(vel.as_meters_per_sec()),
+        }
+    }
+}
 
-pub struct HcSr04 {
-    trig: LineHandle,
-    echo: Line,
-    /// minimum distance reading that will not be ignored
-    dist_threshold: DistanceUnit,
+const SPEED_OF_SOUND: Velocity = Velocity::meters_per_sec(343.0);
+
+/// Which clock the echo pulse width was measured with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TimingSource {
+    /// edge timestamps taken by the kernel in the GPIO interrupt handler
+    Kernel,
+    /// `Instant`s taken after the edges were read, includes scheduler latency
+    Userspace,
 }
 
-fn poll_with_timeout(fd: i32, timeout: Duration) -> Result<bool, HcSr04Error> {
-    let mut pollfd = libc::pollfd {
-        fd,
-        events: libc::POLLIN | libc::POLLPRI,
-        revents: 0,
+pub struct HcSr04<B: EchoBackend = CdevBackend> {
+    backend: B,
+    settings: Settings,
+    last_timing: Option<TimingSource>,
+}
+
+/// Echo pulse width from the kernel timestamps, if both edges have one and they make sense.
+fn kernel_tof(rising: &EdgeEvent, falling: &EdgeEvent, timeout: Duration) -> Option<Duration> {
+    let tof = falling.timestamp?.checked_sub(rising.timestamp?)?;
+    let tof = Duration::from_nanos(tof);
+    if tof.is_zero() || tof > timeout {
+        return None
+    }
+    Some(tof)
+}
+
+/// Echo high time from the first two echo edges after a trigger pulse ending at `start_time`.
+pub(crate) fn echo_time(
+    start_time: Instant,
+    effective_timeout: Duration,
+    rising: Option<EdgeEvent>,
+    falling: Option<EdgeEvent>,
+) -> Result<(Duration, TimingSource), HcSr04Error> {
+    let rising = match rising {
+        Some(event) => event,
+        None => return Err(HcSr04Error::NoEchoStart { timeout: effective_timeout })
     };
+    let rising = (rising.edge == Edge::Rising).then_some(rising);
 
-    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let falling = match falling {
+        Some(event) if event.edge == Edge::Falling => event,
+        _ => return Err(HcSr04Error::EchoNeverEnded { timeout: effective_timeout })
+    };
 
-    unsafe {
-        match libc::poll(&mut pollfd, 1, timeout_ms) {
-            -1 => Err(HcSr04Error::Io),
-            0 => Ok(false),  // Timeout
-            _ => Ok(true),   // Event available
+    let kernel = rising.as_ref().and_then(|rising| kernel_tof(rising, &falling, effective_timeout));
+    let (tof, timing) = match kernel {
+        Some(tof) => (tof, TimingSource::Kernel),
+        None => {
+            let tx_time = rising.map_or(start_time, |rising| rising.at);
+            (falling.at.saturating_duration_since(tx_time), TimingSource::Userspace)
         }
-    }
+    };
+    Ok((tof, timing))
+}
+
+/// Turns the first two echo edges after a trigger pulse ending at `start_time` into a distance in cm.
+pub(crate) fn edges_to_cm(
+    settings: &Settings,
+    start_time: Instant,
+    effective_timeout: Duration,
+    rising: Option<EdgeEvent>,
+    falling: Option<EdgeEvent>,
+) -> Result<(f64, TimingSource), HcSr04Error> {
+    let (tof, timing) = echo_time(start_time, effective_timeout, rising, falling)?;
+    let (dist, _) = settings.echo_to_distance(tof)?;
+    Ok((dist.as_cm(), timing))
+}
+
+/// A trigger pulse that went out, see [`HcSr04::fire`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ping {
+    /// when the trigger pulse ended
+    pub(crate) start_time: Instant,
+    pub(crate) wall_clock: SystemTime,
+    /// how long the echo has to arrive in
+    pub(crate) timeout: Duration,
 }
 
 /// YMMV
-pub fn range_to_timeout(range: DistanceUnit) -> Result<Duration, String> {
-    let res = match range {
-        DistanceUnit::Meter(val) => (val / 2.0) / SPEED_OF_SOUND.to_val(),
-        DistanceUnit::Cm(val) => (val / 200.0) / SPEED_OF_SOUND.to_val(),
-        DistanceUnit::Mm(_) => return Err("range must be in m or cm".to_string())
-    };
-    Ok(Duration::from_secs_f64(res))
+pub fn range_to_timeout(range: Distance) -> Result<Duration, String> {
+    range_to_timeout_with(range, &SpeedOfSound::default())
+}
+
+/// [`range_to_timeout`] for a given speed of sound model.
+pub fn range_to_timeout_with(range: Distance, speed_of_sound: &SpeedOfSound) -> Result<Duration, String> {
+    let res = (range.as_meters() / 2.0) / speed_of_sound.meters_per_sec();
+    match Duration::try_from_secs_f64(res) {
+        Ok(timeout) => Ok(timeout),
+        Err(_) => Err(format!("no timeout for a range of {}", range))
+    }
 }
 
 impl HcSr04 {
-    pub fn new(trig: u32, echo: u32, dist_threshold: DistanceUnit) -> Result<Self, HcSr04Error> {
-        let req_chip = Chip::new("/dev/gpiochip4");
+    /// Uses the controller of the board's GPIO header, see [`find_header_chip`].
+    pub fn new(trig: u32, echo: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Self::builder().pins(trig, echo).min_range(dist_threshold).build()
+    }
 
-        let mut chip = match req_chip.ok() {
-            Some(chip) => chip,
-            None => return Err(HcSr04Error::Init)
-        };
+    /// Opens the chip at `path`, e.g. `/dev/gpiochip0`.
+    pub fn with_chip_path<P: AsRef<Path>>(path: P, trig: u32, echo: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Self::builder().chip_path(path.as_ref()).pins(trig, echo).min_range(dist_threshold).build()
+    }
 
-        let trig_line = match chip.get_line(trig).ok() {
-            Some(line) => line,
-            None => return Err(HcSr04Error::Init)
-        };
+    /// Opens the chip labelled `label`, e.g. `"pinctrl-rp1"`.
+    pub fn with_chip_label(label: &str, trig: u32, echo: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Self::builder().chip_label(label).pins(trig, echo).min_range(dist_threshold).build()
+    }
 
-        let echo_line = match chip.get_line(echo).ok() {
-            Some(line) => line,
-            None => return Err(HcSr04Error::Init)
-        };
+    pub fn with_chip(chip: Chip, trig: u32, echo: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Self::builder().chip(chip).pins(trig, echo).min_range(dist_threshold).build()
+    }
 
-        let trig_handle = match trig_line.request(LineRequestFlags::OUTPUT, 0, "hc-sr04-trigger").ok() {
-            Some(pin) => pin,
-            None => return Err(HcSr04Error::Init)
-        };
+    pub fn builder() -> HcSr04Builder {
+        HcSr04Builder::new()
+    }
+}
 
-        Ok(Self {
-            trig: trig_handle,
-            echo: echo_line,
-            dist_threshold: dist_threshold
-        })
+impl HcSr04<SinglePinBackend> {
+    /// For sensors with one signal pin for trigger and echo, on the controller
+    /// of the board's GPIO header.
+    pub fn single_pin(pin: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        HcSr04Builder::new().signal_pin(pin).min_range(dist_threshold).build_single_pin()
     }
 
-    /// Returns distance in cm by default.
-    fn dist(&mut self, timeout: Option<Duration>) -> Result<Option<f64>, HcSr04Error> {
-        match self.trig.set_value(0).ok() {
-            Some(_) => (),
-            None => return Err(HcSr04Error::Io)
-        }
+    pub fn single_pin_with_chip(chip: Chip, pin: u32, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        HcSr04Builder::new().chip(chip).signal_pin(pin).min_range(dist_threshold).build_single_pin()
+    }
+}
 
-        sleep(Duration::from_micros(2));
+impl<B: EchoBackend> HcSr04<B> {
+    /// Drives the sensor through any [`EchoBackend`], e.g. a [`MockBackend`] in tests.
+    pub fn with_backend(backend: B, dist_threshold: Distance) -> Self {
+        Self::from_parts(backend, Settings::new(dist_threshold))
+    }
 
-        match self.trig.set_value(1).ok() {
-            Some(_) => (),
-            None => return Err(HcSr04Error::Io)
+    pub(crate) fn from_parts(backend: B, settings: Settings) -> Self {
+        Self {
+            backend,
+            settings,
+            last_timing: None
         }
+    }
+
+    /// Readings closer than this are rejected as too near.
+    pub fn min_range(&self) -> Distance {
+        self.settings.dist_threshold
+    }
 
-        sleep(Duration::from_micros(10));
+    /// Checked like [`HcSr04Builder::min_range`]; an invalid range is
+    /// rejected and the old one kept.
+    pub fn set_min_range(&mut self, min: Distance) -> Result<(), HcSr04Error> {
+        check_range(min, self.settings.max_range)?;
+        self.settings.dist_threshold = min;
+        Ok(())
+    }
 
-        match self.trig.set_value(0).ok() {
-            Some(_) => (),
-            None => return Err(HcSr04Error::Io)
-        }
+    /// Readings further than this are rejected, `None` for no limit.
+    pub fn max_range(&self) -> Option<Distance> {
+        self.settings.max_range
+    }
+
+    /// Checked like [`HcSr04Builder::max_range`]; an invalid range is
+    /// rejected and the old one kept.
+    pub fn set_max_range(&mut self, max: Option<Distance>) -> Result<(), HcSr04Error> {
+        check_range(self.settings.dist_threshold, max)?;
+        self.settings.max_range = max;
+        Ok(())
+    }
+
+    pub fn speed_of_sound(&self) -> SpeedOfSound {
+        self.settings.speed_of_sound
+    }
+
+    /// Replaces the speed of sound model, e.g. `SpeedOfSound::Fixed(Velocity::meters_per_sec(340.0))`.
+    pub fn set_speed_of_sound(&mut self, speed_of_sound: SpeedOfSound) {
+        self.settings.speed_of_sound = speed_of_sound;
+    }
+
+    /// Switches to the air model with the given conditions. Call again whenever
+    /// a fresher temperature/humidity reading comes in.
+    pub fn set_environment(&mut self, env: Environment) {
+        self.settings.speed_of_sound = SpeedOfSound::Air(env);
+    }
+
+    /// [`range_to_timeout`] with this sensor's speed of sound model.
+    pub fn range_to_timeout(&self, range: Distance) -> Result<Duration, String> {
+        range_to_timeout_with(range, &self.settings.speed_of_sound)
+    }
+
+    /// Clock used for the last successful reading.
+    pub fn last_timing_source(&self) -> Option<TimingSource> {
+        self.last_timing
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Arms the echo line and fires the trigger pulse.
+    pub(crate) fn fire(&mut self, timeout: Option<Duration>) -> Result<Ping, HcSr04Error> {
+        self.backend.arm_echo()?;
+
+        let backend = &mut self.backend;
+        self.settings.pulse_trigger(|high| backend.set_trigger(high))?;
 
-        let mut dist: Option<f64> = None;
         let start_time = Instant::now();
-        let mut tx_time = Instant::now();
+        self.last_timing = None;
 
-        let events_req = self.echo.events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::BOTH_EDGES,
-            "hc-sr04-echo");
+        Ok(Ping {
+            start_time,
+            wall_clock: SystemTime::now(),
+            timeout: self.settings.effective_timeout(timeout),
+        })
+    }
 
-        let mut events = match events_req.ok() {
-            Some(events) => events,
-            None => return Err(HcSr04Error::LineEventHandleRequest)
-        };
-        let fd = events.as_raw_fd();
+    /// Turns the first two echo edges after [`HcSr04::fire`] into a measurement.
+    pub(crate) fn finish(
+        &mut self,
+        ping: Ping,
+        rising: Option<EdgeEvent>,
+        falling: Option<EdgeEvent>,
+    ) -> Result<Measurement, HcSr04Error> {
+        let (echo_high, timing) = echo_time(ping.start_time, ping.timeout, rising, falling)?;
+        let (distance, clamped) = self.settings.echo_to_distance(echo_high)?;
+        self.last_timing = Some(timing);
+
+        Ok(Measurement {
+            at: ping.start_time,
+            wall_clock: ping.wall_clock,
+            echo_high,
+            timing,
+            wait: ping.start_time.elapsed(),
+            distance,
+            speed_of_sound: self.settings.speed_of_sound.velocity(),
+            flags: MeasurementFlags {
+                clamped,
+                near_threshold: self.settings.near_threshold(distance),
+                retried: false,
+            },
+        })
+    }
 
-        let effective_timeout = match timeout {
-            Some(val) => 2 * val,
-            None => Duration::from_micros(DEFAULT_TIMEOUT_MICROSECS)
+    /// Blocks for the echo of a ping sent with [`HcSr04::fire`].
+    pub(crate) fn wait_echo(&mut self, ping: Ping) -> Result<Measurement, HcSr04Error> {
+        let rising = self.backend.wait_edge(ping.timeout.saturating_sub(ping.start_time.elapsed()))?;
+        let falling = match rising {
+            Some(_) => {
+                let remaining = ping.timeout.saturating_sub(ping.start_time.elapsed());
+                self.backend.wait_edge(remaining)?
+            }
+            None => None
         };
 
-        if !poll_with_timeout(fd, effective_timeout).unwrap_or_else(|_| -> bool {false}) {
-            return Err(HcSr04Error::PollFd)
-        }
-        if let Some(Ok(event)) = events.next() {
-            if event.event_type() == EventType::RisingEdge {
-                tx_time = Instant::now();
-            }
-        }
+        self.finish(ping, rising, falling)
+    }
 
-        let remaining = effective_timeout.saturating_sub(start_time.elapsed());
-        if !poll_with_timeout(fd, remaining).unwrap_or_else(|_| -> bool {false}) {
-            return Err(HcSr04Error::PollFd)
-        }
-        if let Some(Ok(event)) = events.next() {
-            if event.event_type() == EventType::FallingEdge {
-                let tof: Duration = Instant::now() - tx_time;
-                dist = Some(50.0*(SPEED_OF_SOUND.to_val() * tof.as_secs_f64()));
-
-                let dist_threshold = match self.dist_threshold {
-                    DistanceUnit::Cm(val) => val,
-                    DistanceUnit::Mm(val) => val / 10.0,
-                    DistanceUnit::Meter(val) => val * 100.0,
-                };
-
-                if dist < Some(dist_threshold) {
-                    return Ok(None)
-                }
-            }
-        }
-        Ok(dist)
+    /// Returns distance in cm by default.
+    fn dist(&mut self, timeout: Option<Duration>) -> Result<f64, HcSr04Error> {
+        self.measurement(timeout).map(|res| res.distance.as_cm())
     }
 
     /// Returns distance in m. Leaving `timeout` as `None` will give a default timeout of 5.831ms.
-    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<DistanceUnit, HcSr04Error> {
+    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
         let res = self.dist(timeout)?;
-        match res {
-            Some(res) => Ok(DistanceUnit::Meter(res/100.0)),
-            None => Err(HcSr04Error::Io)
-        }
+        Ok(Distance::cm(res).to(LengthUnit::Meter))
     }
 
     /// Returns distance in cm. Leaving `timeout` as `None` will give a default timeout of 5.831ms.
-    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<DistanceUnit, HcSr04Error> {
+    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
         let res = self.dist(timeout)?;
-        match res {
-            Some(res) => Ok(DistanceUnit::Cm(res)),
-            None => Err(HcSr04Error::Io)
-        }
+        Ok(Distance::cm(res))
     }
 
     /// Returns distance in mm. Leaving `timeout` as `None` will give a default timeout of 5.831ms.
-    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<DistanceUnit, HcSr04Error> {
+    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
         let res = self.dist(timeout)?;
-        match res {
-            Some(res) => Ok(DistanceUnit::Mm(10.0*res)),
-            None => Err(HcSr04Error::Io)
-        }
+        Ok(Distance::cm(res).to(LengthUnit::Mm))
     }
 }
@@ -0,0 +1,62 @@
+use crate::{Distance, EchoBackend, HcSr04, HcSr04Error, TimingSource, Velocity, MIN_MEASUREMENT_CYCLE};
+use std::thread::sleep;
+use std::time::*;
+
+/// Things worth knowing about how a [`Measurement`] came about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MeasurementFlags {
+    /// the reading was out of range and replaced by the nearest limit, see
+    /// [`HcSr04Builder::clamp_to_range`](crate::HcSr04Builder::clamp_to_range)
+    pub clamped: bool,
+    /// within the near-threshold margin of a range limit
+    pub near_threshold: bool,
+    /// earlier pings timed out, see [`HcSr04Builder::retries`](crate::HcSr04Builder::retries)
+    pub retried: bool,
+}
+
+/// One reading with everything that went into it. `at` is left out when
+/// serialized, so there is no `Deserialize`: it couldn't be filled back in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Measurement {
+    /// when the trigger pulse ended
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub at: Instant,
+    pub wall_clock: SystemTime,
+    /// how long the echo line was high
+    pub echo_high: Duration,
+    /// clock `echo_high` was measured with
+    pub timing: TimingSource,
+    /// from the end of the trigger pulse until the echo had been read
+    pub wait: Duration,
+    /// in cm
+    pub distance: Distance,
+    pub speed_of_sound: Velocity,
+    pub flags: MeasurementFlags,
+}
+
+impl<B: EchoBackend> HcSr04<B> {
+    /// Like [`HcSr04::dist_cm`], but keeps the timestamps, the raw echo time and
+    /// the speed of sound that went into the distance.
+    ///
+    /// Timed out pings are retried as configured, waiting out the rest of
+    /// [`MIN_MEASUREMENT_CYCLE`] first so the missed echo can't be picked up.
+    pub fn measurement(&mut self, timeout: Option<Duration>) -> Result<Measurement, HcSr04Error> {
+        let mut attempt = 0;
+        loop {
+            let ping = self.fire(timeout)?;
+            match self.wait_echo(ping) {
+                Ok(mut res) => {
+                    res.flags.retried = attempt > 0;
+                    return Ok(res)
+                }
+                Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. }) if attempt < self.settings.retries => {
+                    attempt += 1;
+                    sleep(MIN_MEASUREMENT_CYCLE.saturating_sub(ping.start_time.elapsed()));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
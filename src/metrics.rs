@@ -0,0 +1,389 @@
+use crate::{EchoBackend, HcSr04, HcSr04Error, Measurement};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the echo time histogram, in seconds. 0.2 ms is about 3 cm,
+/// 25 ms a little over 4 m.
+const ECHO_BUCKETS: [f64; 10] = [0.0002, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.015, 0.02, 0.025, 0.03];
+
+/// Window the reading rate gauge is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Reads one counter out of [`SensorStats`].
+type Counter = fn(&SensorStats) -> u64;
+
+#[derive(Debug, Default)]
+struct SensorStats {
+    /// in m
+    latest: Option<f64>,
+    pings: u64,
+    valid: u64,
+    timeouts: u64,
+    below_threshold: u64,
+    above_range: u64,
+    /// faults by [`HcSr04Error::kind`]
+    errors: BTreeMap<&'static str, u64>,
+    /// cumulative counts per bucket of `ECHO_BUCKETS`
+    echo_buckets: [u64; ECHO_BUCKETS.len()],
+    echo_sum: f64,
+    /// recent pings, for the rate
+    recent: VecDeque<Instant>,
+}
+
+impl SensorStats {
+    fn rate(&self, now: Instant) -> f64 {
+        let count = self.recent.iter().filter(|at| now.duration_since(**at) <= RATE_WINDOW).count();
+        count as f64 / RATE_WINDOW.as_secs_f64()
+    }
+}
+
+/// Prometheus metrics for any number of sensors, keyed by a sensor name that
+/// becomes the `sensor` label. Cheap to clone, all clones share the counts.
+///
+/// Exposes, per sensor:
+///
+/// * `hcsr04_distance_meters`: latest valid distance
+/// * `hcsr04_pings_total`, `hcsr04_readings_total`: pings and valid readings
+/// * `hcsr04_reading_rate_hz`: pings per second over the last 10 s
+/// * `hcsr04_timeouts_total`: pings without a complete echo
+/// * `hcsr04_below_threshold_total`, `hcsr04_above_range_total`: out of range readings
+/// * `hcsr04_errors_total{kind}`: everything else, by [`HcSr04Error::kind`]
+/// * `hcsr04_echo_seconds`: histogram of the echo time of valid readings
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    sensors: Arc<Mutex<BTreeMap<String, SensorStats>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the outcome of one [`HcSr04::measurement`].
+    pub fn record(&self, sensor: &str, res: &Result<Measurement, HcSr04Error>) {
+        let mut sensors = self.sensors.lock().unwrap_or_else(|err| err.into_inner());
+        let stats = sensors.entry(sensor.to_string()).or_default();
+
+        let now = Instant::now();
+        stats.pings += 1;
+        stats.recent.push_back(now);
+        while stats.recent.front().is_some_and(|at| now.duration_since(*at) > RATE_WINDOW) {
+            stats.recent.pop_front();
+        }
+
+        match res {
+            Ok(res) => {
+                let echo = res.echo_high.as_secs_f64();
+                stats.valid += 1;
+                stats.latest = Some(res.distance.as_meters());
+                stats.echo_sum += echo;
+                for (count, le) in stats.echo_buckets.iter_mut().zip(ECHO_BUCKETS) {
+                    if echo <= le {
+                        *count += 1;
+                    }
+                }
+            }
+            Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. }) => stats.timeouts += 1,
+            Err(HcSr04Error::OutOfRange { distance, min, .. }) if distance < min => stats.below_threshold += 1,
+            Err(HcSr04Error::OutOfRange { .. }) => stats.above_range += 1,
+            Err(err) => *stats.errors.entry(err.kind()).or_default() += 1,
+        }
+    }
+
+    /// Takes a measurement and records it.
+    pub fn measure<B: EchoBackend>(
+        &self,
+        sensor: &str,
+        hcsr04: &mut HcSr04<B>,
+        timeout: Option<Duration>,
+    ) -> Result<Measurement, HcSr04Error> {
+        let res = hcsr04.measurement(timeout);
+        self.record(sensor, &res);
+        res
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let sensors = self.sensors.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let mut out = String::new();
+
+        let mut family = |name: &str, kind: &str, help: &str, sample: &dyn Fn(&mut String, &str, &SensorStats)| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (sensor, stats) in sensors.iter() {
+                sample(&mut out, &escape_label(sensor), stats);
+            }
+        };
+
+        family("hcsr04_distance_meters", "gauge", "Latest valid distance.", &|out, sensor, stats| {
+            if let Some(latest) = stats.latest {
+                let _ = writeln!(out, "hcsr04_distance_meters{{sensor=\"{}\"}} {}", sensor, latest);
+            }
+        });
+        let counters: [(&str, &str, Counter); 5] = [
+            ("hcsr04_pings_total", "Pings sent, whatever came of them.", |stats| stats.pings),
+            ("hcsr04_readings_total", "Valid readings.", |stats| stats.valid),
+            ("hcsr04_timeouts_total", "Pings without a complete echo.", |stats| stats.timeouts),
+            ("hcsr04_below_threshold_total", "Readings closer than the minimum range.", |stats| stats.below_threshold),
+            ("hcsr04_above_range_total", "Readings beyond the maximum range.", |stats| stats.above_range),
+        ];
+        for (name, help, value) in counters {
+            family(name, "counter", help, &|out, sensor, stats| {
+                let _ = writeln!(out, "{}{{sensor=\"{}\"}} {}", name, sensor, value(stats));
+            });
+        }
+        family("hcsr04_errors_total", "counter", "Failed pings by error kind.", &|out, sensor, stats| {
+            for (kind, count) in &stats.errors {
+                let _ = writeln!(out, "hcsr04_errors_total{{sensor=\"{}\",kind=\"{}\"}} {}", sensor, kind, count);
+            }
+        });
+        family("hcsr04_reading_rate_hz", "gauge", "Pings per second over the last 10 s.", &|out, sensor, stats| {
+            let _ = writeln!(out, "hcsr04_reading_rate_hz{{sensor=\"{}\"}} {}", sensor, stats.rate(now));
+        });
+        family("hcsr04_echo_seconds", "histogram", "Echo time of valid readings.", &|out, sensor, stats| {
+            for (count, le) in stats.echo_buckets.iter().zip(ECHO_BUCKETS) {
+                let _ = writeln!(out, "hcsr04_echo_seconds_bucket{{sensor=\"{}\",le=\"{}\"}} {}", sensor, le, count);
+            }
+            let _ = writeln!(out, "hcsr04_echo_seconds_bucket{{sensor=\"{}\",le=\"+Inf\"}} {}", sensor, stats.valid);
+            let _ = writeln!(out, "hcsr04_echo_seconds_sum{{sensor=\"{}\"}} {}", sensor, stats.echo_sum);
+            let _ = writeln!(out, "hcsr04_echo_seconds_count{{sensor=\"{}\"}} {}", sensor, stats.valid);
+        });
+        out
+    }
+
+    /// Serves [`Metrics::render`] on `GET /metrics` from a background thread,
+    /// e.g. on `0.0.0.0:9100`. Bind port 0 to have one picked, see
+    /// [`MetricsServer::local_addr`].
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let metrics = self.clone();
+        let stopped = stop.clone();
+        let handle = thread::Builder::new().name("hcsr04-metrics".to_string()).spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break
+                }
+                // a client that hangs up early only affects itself
+                if let Ok(stream) = stream {
+                    let _ = respond(stream, &metrics);
+                }
+            }
+        })?;
+
+        Ok(MetricsServer { addr, stop, handle: Some(handle) })
+    }
+}
+
+/// The HTTP endpoint started by [`Metrics::serve`]. Stops when dropped.
+#[derive(Debug)]
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections and waits for the server thread.
+    pub fn shutdown(mut self) {
+        self.stop_thread();
+    }
+
+    fn stop_thread(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return
+        };
+        self.stop.store(true, Ordering::SeqCst);
+
+        // wake the blocking accept
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if TcpStream::connect_timeout(&wake, Duration::from_secs(1)).is_ok() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+/// Answers one HTTP/1.x request and closes the connection.
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/metrics") => ("200 OK", metrics.render()),
+        (_, "/metrics") => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    stream.write_all(response.as_bytes())
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Distance, MockBackend, MockEcho};
+
+    fn sample<'a>(rendered: &'a str, series: &str) -> Option<&'a str> {
+        rendered.lines().find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+    }
+
+    fn recorded() -> Metrics {
+        let metrics = Metrics::new();
+        let backend = MockBackend::with_script([
+            MockEcho::from_distance(Distance::cm(10.0)),
+            MockEcho::from_distance(Distance::cm(300.0)),
+            MockEcho::NoEcho,
+            MockEcho::NoFall,
+        ]);
+        let mut hcsr04 = HcSr04::with_backend(backend, Distance::cm(2.0));
+        let timeout = Some(Duration::from_millis(20));
+        for _ in 0..4 {
+            let _ = metrics.measure("front", &mut hcsr04, timeout);
+        }
+
+        let out_of_range = |cm| HcSr04Error::OutOfRange {
+            distance: Distance::cm(cm),
+            min: Distance::cm(2.0),
+            max: Some(Distance::cm(400.0)),
+        };
+        metrics.record("front", &Err(out_of_range(1.0)));
+        metrics.record("front", &Err(out_of_range(500.0)));
+        metrics.record("front", &Err(out_of_range(450.0)));
+        metrics.record("front", &Err(HcSr04Error::InvalidConfig("broken".to_string())));
+        metrics.record("back\"", &Err(HcSr04Error::InvalidConfig("broken".to_string())));
+        metrics
+    }
+
+    #[test]
+    fn renders_every_outcome() {
+        let rendered = recorded().render();
+        let front = |name: &str| sample(&rendered, &format!("{}{{sensor=\"front\"}}", name));
+
+        let latest: f64 = front("hcsr04_distance_meters").unwrap().parse().unwrap();
+        assert!((latest - 3.0).abs() < 1e-6, "{}", latest);
+        assert_eq!(front("hcsr04_pings_total"), Some("8"));
+        assert_eq!(front("hcsr04_readings_total"), Some("2"));
+        assert_eq!(front("hcsr04_timeouts_total"), Some("2"));
+        assert_eq!(front("hcsr04_below_threshold_total"), Some("1"));
+        assert_eq!(front("hcsr04_above_range_total"), Some("2"));
+        assert_eq!(sample(&rendered, "hcsr04_errors_total{sensor=\"front\",kind=\"invalid_config\"}"), Some("1"));
+        assert_eq!(front("hcsr04_reading_rate_hz"), Some("0.8"));
+
+        // label values are escaped
+        assert_eq!(sample(&rendered, "hcsr04_pings_total{sensor=\"back\\\"\"}"), Some("1"));
+        assert_eq!(sample(&rendered, "hcsr04_distance_meters{sensor=\"back\\\"\"}"), None);
+
+        assert!(rendered.contains("# TYPE hcsr04_echo_seconds histogram\n"));
+        assert!(rendered.contains("# TYPE hcsr04_pings_total counter\n"));
+    }
+
+    #[test]
+    fn echo_histogram_is_cumulative() {
+        let rendered = recorded().render();
+        let bucket = |le: &str| sample(&rendered, &format!("hcsr04_echo_seconds_bucket{{sensor=\"front\",le=\"{}\"}}", le));
+
+        // 10 cm is a 0.58 ms echo, 3 m a 17.5 ms one
+        assert_eq!(bucket("0.0005"), Some("0"));
+        assert_eq!(bucket("0.001"), Some("1"));
+        assert_eq!(bucket("0.015"), Some("1"));
+        assert_eq!(bucket("0.02"), Some("2"));
+        assert_eq!(bucket("0.03"), Some("2"));
+        assert_eq!(bucket("+Inf"), Some("2"));
+        assert_eq!(sample(&rendered, "hcsr04_echo_seconds_count{sensor=\"front\"}"), Some("2"));
+
+        let sum: f64 = sample(&rendered, "hcsr04_echo_seconds_sum{sensor=\"front\"}").unwrap().parse().unwrap();
+        assert!((sum - 3.1 * 2.0 / 343.0).abs() < 1e-6, "{}", sum);
+
+        let counts: Vec<u64> = rendered
+            .lines()
+            .filter(|line| line.starts_with("hcsr04_echo_seconds_bucket{sensor=\"front\""))
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(counts.len(), ECHO_BUCKETS.len() + 1);
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_over_http() {
+        let metrics = recorded();
+        let server = metrics.serve("127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("hcsr04_pings_total{sensor=\"front\"} 8\n"));
+
+        // recorded after the server started
+        metrics.record("front", &Err(HcSr04Error::NoEchoStart { timeout: Duration::from_millis(20) }));
+        assert!(get(addr, "GET /metrics?x=1 HTTP/1.0\r\n\r\n").contains("hcsr04_pings_total{sensor=\"front\"} 9\n"));
+
+        assert!(get(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(get(addr, "POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+
+        server.shutdown();
+        // the thread is gone and took the listener with it
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
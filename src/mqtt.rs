@@ -0,0 +1,617 @@
+use crate::output::json_string;
+use crate::{LengthUnit, OutputFormat, RangeSensor, Reading, ReadingWriter};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+/// MQTT delivery guarantee. Exactly-once isn't supported, a repeated distance
+/// does no harm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum QoS {
+    /// fire and forget
+    #[default]
+    AtMostOnce,
+    /// wait for the broker's acknowledgement
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn level(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+}
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Configures and connects an [`MqttPublisher`].
+#[derive(Debug, Clone)]
+pub struct MqttPublisherBuilder {
+    broker: String,
+    sensor_id: String,
+    client_id: Option<String>,
+    credentials: Option<(String, String)>,
+    keep_alive: Duration,
+    state_topic: Option<String>,
+    availability_topic: Option<String>,
+    qos: QoS,
+    retain: bool,
+    discovery_prefix: Option<String>,
+    name: Option<String>,
+    unit: LengthUnit,
+    interval: Duration,
+    io_timeout: Duration,
+}
+
+impl MqttPublisherBuilder {
+    /// `broker` is a `host:port` address, `sensor_id` names the sensor in the
+    /// default topics and in Home Assistant.
+    pub fn new<A: Into<String>, I: Into<String>>(broker: A, sensor_id: I) -> Self {
+        MqttPublisherBuilder {
+            broker: broker.into(),
+            sensor_id: sensor_id.into(),
+            client_id: None,
+            credentials: None,
+            keep_alive: Duration::from_secs(60),
+            state_topic: None,
+            availability_topic: None,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            discovery_prefix: None,
+            name: None,
+            unit: LengthUnit::Cm,
+            interval: Duration::from_secs(1),
+            io_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Client id sent to the broker, `hcsr04-<sensor id>` by default.
+    pub fn client_id<S: Into<String>>(mut self, client_id: S) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    pub fn credentials<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Longest silence before the broker drops the connection and publishes
+    /// the last will, 60 s by default. Whole seconds, at most 18 hours.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Where readings go, `hcsr04/<sensor id>/state` by default.
+    pub fn state_topic<S: Into<String>>(mut self, topic: S) -> Self {
+        self.state_topic = Some(topic.into());
+        self
+    }
+
+    /// Where `online`/`offline` go, `hcsr04/<sensor id>/availability` by
+    /// default. Always retained, `offline` is also the last will.
+    pub fn availability_topic<S: Into<String>>(mut self, topic: S) -> Self {
+        self.availability_topic = Some(topic.into());
+        self
+    }
+
+    /// QoS of everything published, including the last will.
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Whether readings are retained, so a new subscriber gets the latest one
+    /// right away. Off by default.
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Publishes Home Assistant discovery config under `prefix`, normally
+    /// `homeassistant`, when connecting.
+    pub fn home_assistant<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.discovery_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Entity and device name shown in Home Assistant, the sensor id by
+    /// default.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Unit the distance is published in, cm by default.
+    pub fn unit(mut self, unit: LengthUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Time between readings in [`MqttPublisher::run`], 1 s by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long to wait for the broker to answer, 10 s by default.
+    pub fn io_timeout(mut self, timeout: Duration) -> Self {
+        self.io_timeout = timeout;
+        self
+    }
+
+    /// Connects, marks the sensor online and publishes the discovery config.
+    pub fn connect<S: RangeSensor>(self, sensor: S) -> io::Result<MqttPublisher<S>> {
+        if self.sensor_id.is_empty() {
+            return Err(invalid_input("sensor id must not be empty"))
+        }
+        if self.keep_alive.as_secs() > u16::MAX as u64 {
+            return Err(invalid_input("keep alive must be at most 65535 s"))
+        }
+        if self.io_timeout.is_zero() {
+            return Err(invalid_input("I/O timeout must be non-zero"))
+        }
+
+        let stream = TcpStream::connect(&self.broker)?;
+        stream.set_read_timeout(Some(self.io_timeout))?;
+        stream.set_write_timeout(Some(self.io_timeout))?;
+        stream.set_nodelay(true)?;
+
+        let writer = ReadingWriter::new(io::sink(), OutputFormat::JsonLines).with_unit(self.unit);
+        let mut publisher = MqttPublisher {
+            state_topic: self.state_topic.clone().unwrap_or(format!("hcsr04/{}/state", self.sensor_id)),
+            availability_topic: self
+                .availability_topic
+                .clone()
+                .unwrap_or(format!("hcsr04/{}/availability", self.sensor_id)),
+            config: self,
+            sensor,
+            stream,
+            writer,
+            next_packet_id: 1,
+            last_sent: Instant::now(),
+        };
+        publisher.handshake()?;
+        Ok(publisher)
+    }
+}
+
+/// Measures periodically with any [`RangeSensor`] and publishes the readings to
+/// an MQTT 3.1.1 broker, with availability through the last will and
+/// optionally Home Assistant discovery.
+///
+/// Readings are the [`OutputFormat::JsonLines`] records of [`ReadingWriter`],
+/// so a fault or an out of range reading arrives with a `null` distance and its
+/// `status`. Plain TCP only; put a local broker or a TLS tunnel in front for
+/// anything else.
+#[derive(Debug)]
+pub struct MqttPublisher<S: RangeSensor> {
+    config: MqttPublisherBuilder,
+    state_topic: String,
+    availability_topic: String,
+    sensor: S,
+    stream: TcpStream,
+    writer: ReadingWriter<io::Sink>,
+    next_packet_id: u16,
+    last_sent: Instant,
+}
+
+impl<S: RangeSensor> MqttPublisher<S> {
+    pub fn state_topic(&self) -> &str {
+        &self.state_topic
+    }
+
+    pub fn availability_topic(&self) -> &str {
+        &self.availability_topic
+    }
+
+    /// Where the Home Assistant discovery config goes, if enabled.
+    pub fn discovery_topic(&self) -> Option<String> {
+        let prefix = self.config.discovery_prefix.as_ref()?;
+        Some(format!("{}/sensor/{}/config", prefix, self.object_id()))
+    }
+
+    /// Home Assistant discovery config for a distance sensor entity on the
+    /// state and availability topics.
+    pub fn discovery_config(&self) -> String {
+        let unique_id = json_string(&self.object_id());
+        let name = json_string(self.config.name.as_deref().unwrap_or(&self.config.sensor_id));
+        format!(
+            concat!(
+                "{{\"name\":{name},\"unique_id\":{id},",
+                "\"state_topic\":{state},\"json_attributes_topic\":{state},",
+                "\"value_template\":\"{{{{ value_json.distance }}}}\",",
+                "\"device_class\":\"distance\",\"state_class\":\"measurement\",\"unit_of_measurement\":\"{unit}\",",
+                "\"availability_topic\":{availability},\"payload_available\":\"{online}\",\"payload_not_available\":\"{offline}\",",
+                "\"qos\":{qos},",
+                "\"device\":{{\"identifiers\":[{id}],\"name\":{name},\"model\":{model}}}}}"
+            ),
+            name = name,
+            id = unique_id,
+            state = json_string(&self.state_topic),
+            unit = self.config.unit.suffix(),
+            availability = json_string(&self.availability_topic),
+            online = ONLINE,
+            offline = OFFLINE,
+            qos = self.config.qos.level(),
+            model = json_string(self.sensor.name()),
+        )
+    }
+
+    pub fn sensor(&self) -> &S {
+        &self.sensor
+    }
+
+    pub fn sensor_mut(&mut self) -> &mut S {
+        &mut self.sensor
+    }
+
+    /// Takes one reading and publishes it.
+    pub fn publish_reading(&mut self) -> io::Result<Reading> {
+        let wall_clock = SystemTime::now();
+        let reading = self.sensor.read();
+        let payload = self.writer.render(&self.config.sensor_id, wall_clock, &reading);
+        let topic = self.state_topic.clone();
+        self.publish(&topic, payload.as_bytes(), self.config.retain)?;
+        Ok(reading)
+    }
+
+    /// Publishes a reading every interval until the connection fails, pinging
+    /// the broker in between if the interval is longer than half the keep
+    /// alive.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut next = Instant::now();
+        loop {
+            self.publish_reading()?;
+            next += self.config.interval;
+            self.idle_until(next)?;
+        }
+    }
+
+    /// Sleeps until `deadline`, keeping the connection alive.
+    pub fn idle_until(&mut self, deadline: Instant) -> io::Result<()> {
+        let ping_every = self.config.keep_alive / 2;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(())
+            }
+            if !ping_every.is_zero() && now.duration_since(self.last_sent) >= ping_every {
+                self.ping()?;
+                continue;
+            }
+            let until_ping = match ping_every.is_zero() {
+                true => deadline - now,
+                false => (self.last_sent + ping_every).saturating_duration_since(now),
+            };
+            sleep(until_ping.min(deadline - now));
+        }
+    }
+
+    /// Round trip to the broker.
+    pub fn ping(&mut self) -> io::Result<()> {
+        self.send(&packet(PINGREQ, &[]))?;
+        loop {
+            let (header, _) = self.read_packet()?;
+            if header >> 4 == PINGRESP >> 4 {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Marks the sensor offline, disconnects cleanly and hands the sensor back.
+    pub fn disconnect(mut self) -> io::Result<S> {
+        let topic = self.availability_topic.clone();
+        self.publish(&topic, OFFLINE.as_bytes(), true)?;
+        self.send(&packet(DISCONNECT, &[]))?;
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        Ok(self.sensor)
+    }
+
+    /// Discovery object id, the sensor id with anything but `[A-Za-z0-9_-]`
+    /// replaced.
+    fn object_id(&self) -> String {
+        let id: String = self
+            .config
+            .sensor_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        format!("{}_distance", id)
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let client_id = self.config.client_id.clone().unwrap_or(format!("hcsr04-{}", self.config.sensor_id));
+
+        let mut body = Vec::new();
+        put_bytes(&mut body, b"MQTT")?;
+        body.push(4);
+        body.push(connect_flags(self.config.qos, self.config.credentials.is_some()));
+        body.extend_from_slice(&(self.config.keep_alive.as_secs() as u16).to_be_bytes());
+        put_bytes(&mut body, client_id.as_bytes())?;
+        put_bytes(&mut body, self.availability_topic.as_bytes())?;
+        put_bytes(&mut body, OFFLINE.as_bytes())?;
+        if let Some((username, password)) = &self.config.credentials {
+            put_bytes(&mut body, username.as_bytes())?;
+            put_bytes(&mut body, password.as_bytes())?;
+        }
+        self.send(&packet(CONNECT, &body))?;
+
+        let (header, body) = self.read_packet()?;
+        if header != CONNACK || body.len() != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK from the broker"))
+        }
+        match body[1] {
+            0 => {}
+            1 => return Err(refused("unacceptable protocol version")),
+            2 => return Err(refused("client id rejected")),
+            3 => return Err(refused("server unavailable")),
+            4 => return Err(refused("bad username or password")),
+            5 => return Err(refused("not authorized")),
+            code => return Err(refused(&format!("return code {}", code))),
+        }
+
+        let topic = self.availability_topic.clone();
+        self.publish(&topic, ONLINE.as_bytes(), true)?;
+        if let Some(topic) = self.discovery_topic() {
+            let config = self.discovery_config();
+            self.publish(&topic, config.as_bytes(), true)?;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let qos = self.config.qos;
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+        put_bytes(&mut body, topic.as_bytes())?;
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => {
+                let id = self.next_packet_id;
+                self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+                body.extend_from_slice(&id.to_be_bytes());
+                Some(id)
+            }
+        };
+        body.extend_from_slice(payload);
+        self.send(&packet(PUBLISH | (qos.level() << 1) | retain as u8, &body))?;
+
+        let packet_id = match packet_id {
+            Some(id) => id,
+            None => return Ok(())
+        };
+        loop {
+            let (header, body) = self.read_packet()?;
+            if header == PUBACK && body.get(..2) == Some(&packet_id.to_be_bytes()[..]) {
+                return Ok(())
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        read_packet(&mut self.stream)
+    }
+}
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// Fixed header and body of one control packet.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(header);
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// Fixed header byte and body of the next control packet.
+fn read_packet<R: Read>(stream: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut len = 0usize;
+    for shift in [0, 7, 14, 21] {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body)?;
+            return Ok((header, body))
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "malformed MQTT remaining length"))
+}
+
+/// CONNECT flags: clean session and a retained last will at `qos`, plus
+/// username and password if there are credentials.
+fn connect_flags(qos: QoS, credentials: bool) -> u8 {
+    let mut flags = 0x02 | 0x04 | (qos.level() << 3) | 0x20;
+    if credentials {
+        flags |= 0x80 | 0x40;
+    }
+    flags
+}
+
+/// Length-prefixed string or binary field.
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    let len = match u16::try_from(bytes.len()) {
+        Ok(len) => len,
+        Err(_) => return Err(invalid_input("MQTT string longer than 65535 bytes"))
+    };
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn refused(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, format!("MQTT broker refused the connection: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Distance, HcSr04, MockBackend, MockEcho};
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn remaining_length_encoding() {
+        assert_eq!(packet(PINGREQ, &[]), [PINGREQ, 0]);
+        assert_eq!(packet(PUBLISH, &[7; 127])[..2], [PUBLISH, 127]);
+        assert_eq!(packet(PUBLISH, &[7; 200])[..3], [PUBLISH, 0xc8, 0x01]);
+        assert_eq!(packet(PUBLISH, &[7; 16384])[..4], [PUBLISH, 0x80, 0x80, 0x01]);
+
+        for len in [0, 1, 127, 128, 16383, 16384, 70000] {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let (header, read) = read_packet(&mut Cursor::new(packet(PUBLISH | 0x01, &body))).unwrap();
+            assert_eq!(header, PUBLISH | 0x01);
+            assert_eq!(read, body);
+        }
+
+        let err = read_packet(&mut Cursor::new([PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn connect_flag_byte() {
+        assert_eq!(connect_flags(QoS::AtMostOnce, false), 0b0010_0110);
+        assert_eq!(connect_flags(QoS::AtLeastOnce, false), 0b0010_1110);
+        assert_eq!(connect_flags(QoS::AtLeastOnce, true), 0b1110_1110);
+    }
+
+    fn take_bytes(body: &[u8], pos: &mut usize) -> Vec<u8> {
+        let len = u16::from_be_bytes([body[*pos], body[*pos + 1]]) as usize;
+        let bytes = body[*pos + 2..*pos + 2 + len].to_vec();
+        *pos += 2 + len;
+        bytes
+    }
+
+    fn take_string(body: &[u8], pos: &mut usize) -> String {
+        String::from_utf8(take_bytes(body, pos)).unwrap()
+    }
+
+    #[derive(Debug)]
+    struct Publish {
+        topic: String,
+        payload: String,
+        qos: u8,
+        retain: bool,
+    }
+
+    #[test]
+    fn publishes_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a broker that acknowledges everything and records what it got
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (header, connect) = read_packet(&mut stream).unwrap();
+            assert_eq!(header, CONNECT);
+            stream.write_all(&packet(CONNACK, &[0, 0])).unwrap();
+
+            let mut publishes = Vec::new();
+            loop {
+                let (header, body) = read_packet(&mut stream).unwrap();
+                if header == DISCONNECT {
+                    return (connect, publishes)
+                }
+                assert_eq!(header >> 4, PUBLISH >> 4);
+                let qos = (header >> 1) & 0x03;
+                let mut pos = 0;
+                let topic = take_string(&body, &mut pos);
+                if qos > 0 {
+                    stream.write_all(&packet(PUBACK, &body[pos..pos + 2])).unwrap();
+                    pos += 2;
+                }
+                let payload = String::from_utf8(body[pos..].to_vec()).unwrap();
+                publishes.push(Publish { topic, payload, qos, retain: header & 0x01 != 0 });
+            }
+        });
+
+        let sensor = HcSr04::with_backend(
+            MockBackend::with_script([MockEcho::from_distance(Distance::cm(42.0))]),
+            Distance::cm(2.0),
+        );
+        let mut publisher = MqttPublisherBuilder::new(addr.to_string(), "tank")
+            .credentials("user", "secret")
+            .keep_alive(Duration::from_secs(30))
+            .qos(QoS::AtLeastOnce)
+            .home_assistant("homeassistant")
+            .io_timeout(Duration::from_secs(5))
+            .connect(sensor)
+            .unwrap();
+        assert!(publisher.publish_reading().unwrap().is_valid());
+        publisher.disconnect().unwrap();
+
+        let (connect, publishes) = broker.join().unwrap();
+        let mut pos = 0;
+        assert_eq!(take_string(&connect, &mut pos), "MQTT");
+        assert_eq!(connect[pos..pos + 4], [4, 0b1110_1110, 0, 30]);
+        pos += 4;
+        assert_eq!(take_string(&connect, &mut pos), "hcsr04-tank");
+        assert_eq!(take_string(&connect, &mut pos), "hcsr04/tank/availability");
+        assert_eq!(take_string(&connect, &mut pos), OFFLINE);
+        assert_eq!(take_string(&connect, &mut pos), "user");
+        assert_eq!(take_string(&connect, &mut pos), "secret");
+        assert_eq!(pos, connect.len());
+
+        let topics: Vec<&str> = publishes.iter().map(|publish| publish.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "hcsr04/tank/availability",
+                "homeassistant/sensor/tank_distance/config",
+                "hcsr04/tank/state",
+                "hcsr04/tank/availability",
+            ]
+        );
+        assert!(publishes.iter().all(|publish| publish.qos == 1));
+
+        let (online, discovery, state, offline) = (&publishes[0], &publishes[1], &publishes[2], &publishes[3]);
+        assert!(online.retain && online.payload == ONLINE);
+        assert!(discovery.retain);
+        assert!(discovery.payload.contains("\"state_topic\":\"hcsr04/tank/state\""));
+        assert!(discovery.payload.contains("\"unique_id\":\"tank_distance\""));
+        assert!(!state.retain);
+        assert!(state.payload.contains("\"status\":\"valid\""));
+        assert!(state.payload.contains("\"distance\":42.00"));
+        assert!(offline.retain && offline.payload == OFFLINE);
+    }
+}
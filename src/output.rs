@@ -0,0 +1,348 @@
+use crate::{LengthUnit, Reading};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Line-oriented formats for streaming readings into other tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OutputFormat {
+    /// one JSON object per line
+    JsonLines,
+    /// comma-separated values, with a header line before the first reading
+    Csv,
+    /// InfluxDB line protocol, nanosecond timestamps
+    Influx,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    /// Accepts `json`/`jsonl`, `csv` and `influx`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" | "jsonl" => Ok(OutputFormat::JsonLines),
+            "csv" => Ok(OutputFormat::Csv),
+            "influx" => Ok(OutputFormat::Influx),
+            _ => Err(format!("unknown output format {:?}, expected json, csv or influx", s)),
+        }
+    }
+}
+
+/// Renders [`Reading`]s as lines of an [`OutputFormat`] and writes them out,
+/// flushing after every line so that a pipe sees each reading as it's taken.
+///
+/// Every line carries the time, a sensor id, the [`Reading::status`], the
+/// distance if there is one and the error of a fault:
+///
+/// ```text
+/// {"time":1760000000.123,"sensor":"front","status":"valid","distance":48.27,"unit":"cm","error":null}
+///
+/// time,sensor,status,distance_cm,error
+/// 1760000000.123,front,valid,48.27,
+///
+/// distance,sensor=front status="valid",distance_cm=48.27 1760000000123000000
+/// ```
+#[derive(Debug)]
+pub struct ReadingWriter<W: Write> {
+    out: W,
+    format: OutputFormat,
+    unit: LengthUnit,
+    measurement: String,
+    header_written: bool,
+}
+
+impl<W: Write> ReadingWriter<W> {
+    /// Writes distances in cm, under the InfluxDB measurement `distance`.
+    pub fn new(out: W, format: OutputFormat) -> Self {
+        ReadingWriter {
+            out,
+            format,
+            unit: LengthUnit::Cm,
+            measurement: "distance".to_string(),
+            header_written: false,
+        }
+    }
+
+    /// Unit the distances are written in.
+    pub fn with_unit(mut self, unit: LengthUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// InfluxDB measurement name, ignored by the other formats.
+    pub fn with_measurement(mut self, measurement: impl Into<String>) -> Self {
+        self.measurement = measurement.into();
+        self
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn unit(&self) -> LengthUnit {
+        self.unit
+    }
+
+    /// The CSV header, `None` for formats without one.
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            OutputFormat::Csv => Some(format!("time,sensor,status,distance_{},error", self.unit.suffix())),
+            _ => None,
+        }
+    }
+
+    /// One reading as a line, without the trailing newline.
+    pub fn render(&self, sensor: &str, at: SystemTime, reading: &Reading) -> String {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let distance = match reading {
+            Reading::Valid(distance) | Reading::TooNear(distance) | Reading::OutOfRange(Some(distance)) => {
+                Some(distance.value_in(self.unit)).filter(|val| val.is_finite())
+            }
+            _ => None,
+        };
+        let distance = distance.map(|val| format!("{:.*}", decimals(self.unit), val));
+        let error = match reading {
+            Reading::Fault(err) => Some(err.to_string()),
+            _ => None,
+        };
+
+        let mut line = String::new();
+        match self.format {
+            OutputFormat::JsonLines => {
+                let _ = write!(
+                    line,
+                    "{{\"time\":{:.3},\"sensor\":{},\"status\":\"{}\",\"distance\":{},\"unit\":\"{}\",\"error\":{}}}",
+                    since_epoch.as_secs_f64(),
+                    json_string(sensor),
+                    reading.status(),
+                    distance.as_deref().unwrap_or("null"),
+                    self.unit.suffix(),
+                    error.as_deref().map(json_string).unwrap_or("null".to_string())
+                );
+            }
+            OutputFormat::Csv => {
+                let _ = write!(
+                    line,
+                    "{:.3},{},{},{},{}",
+                    since_epoch.as_secs_f64(),
+                    csv_field(sensor),
+                    reading.status(),
+                    distance.as_deref().unwrap_or(""),
+                    error.as_deref().map(csv_field).unwrap_or_default()
+                );
+            }
+            OutputFormat::Influx => {
+                let _ = write!(
+                    line,
+                    "{},sensor={} status=\"{}\"",
+                    influx_escape(&self.measurement, &[',', ' ']),
+                    influx_escape(sensor, &[',', '=', ' ']),
+                    reading.status()
+                );
+                if let Some(distance) = &distance {
+                    let _ = write!(line, ",distance_{}={}", self.unit.suffix(), distance);
+                }
+                if let Some(error) = &error {
+                    let _ = write!(line, ",error=\"{}\"", influx_escape(error, &['"']));
+                }
+                let _ = write!(line, " {}", since_epoch.as_nanos());
+            }
+        }
+        line
+    }
+
+    /// Writes one reading, preceded by the header if this is the first, and
+    /// flushes.
+    pub fn write(&mut self, sensor: &str, at: SystemTime, reading: &Reading) -> io::Result<()> {
+        if !self.header_written {
+            self.header_written = true;
+            if let Some(header) = self.header() {
+                writeln!(self.out, "{}", header)?;
+            }
+        }
+        let line = self.render(sensor, at, reading);
+        writeln!(self.out, "{}", line)?;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Enough decimals for a tenth of a millimetre.
+fn decimals(unit: LengthUnit) -> usize {
+    match unit {
+        LengthUnit::Mm => 1,
+        LengthUnit::Cm => 2,
+        LengthUnit::Meter => 4,
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Quotes the field if needed; newlines become spaces to keep one reading
+/// per line.
+fn csv_field(s: &str) -> String {
+    let s = s.replace(['\n', '\r'], " ");
+    match s.contains([',', '"']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s,
+    }
+}
+
+/// Backslash-escapes `special` and backslashes; newlines can't be escaped in
+/// line protocol so they become spaces.
+fn influx_escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' | '\r' => out.push(' '),
+            c if c == '\\' || special.contains(&c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Distance, HcSr04Error};
+
+    /// Needs quoting or escaping in every format.
+    const AWKWARD: &str = "front \"left\", a\\b";
+
+    fn at() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_760_000_000_123)
+    }
+
+    fn fault() -> Reading {
+        Reading::Fault(HcSr04Error::InvalidConfig("bad \"x\"\nline".to_string()))
+    }
+
+    fn render(format: OutputFormat, sensor: &str, reading: &Reading) -> String {
+        ReadingWriter::new(Vec::new(), format).render(sensor, at(), reading)
+    }
+
+    #[test]
+    fn json_lines() {
+        let format = OutputFormat::JsonLines;
+        assert_eq!(
+            render(format, "front", &Reading::Valid(Distance::cm(48.27))),
+            r#"{"time":1760000000.123,"sensor":"front","status":"valid","distance":48.27,"unit":"cm","error":null}"#
+        );
+        assert_eq!(
+            render(format, "front", &Reading::TooNear(Distance::cm(1.5))),
+            r#"{"time":1760000000.123,"sensor":"front","status":"too_near","distance":1.50,"unit":"cm","error":null}"#
+        );
+        assert_eq!(
+            render(format, "front", &Reading::OutOfRange(None)),
+            r#"{"time":1760000000.123,"sensor":"front","status":"out_of_range","distance":null,"unit":"cm","error":null}"#
+        );
+        assert_eq!(
+            render(format, AWKWARD, &fault()),
+            r#"{"time":1760000000.123,"sensor":"front \"left\", a\\b","status":"fault","distance":null,"unit":"cm","error":"invalid configuration: bad \"x\"\nline"}"#
+        );
+        assert_eq!(json_string("tab\there\u{1}"), r#""tab\u0009here\u0001""#);
+    }
+
+    #[test]
+    fn csv() {
+        let format = OutputFormat::Csv;
+        assert_eq!(render(format, "front", &Reading::Valid(Distance::cm(48.27))), "1760000000.123,front,valid,48.27,");
+        assert_eq!(
+            render(format, "front", &Reading::OutOfRange(Some(Distance::cm(512.0)))),
+            "1760000000.123,front,out_of_range,512.00,"
+        );
+        assert_eq!(render(format, "front", &Reading::OutOfRange(None)), "1760000000.123,front,out_of_range,,");
+        assert_eq!(
+            render(format, AWKWARD, &fault()),
+            r#"1760000000.123,"front ""left"", a\b",fault,,"invalid configuration: bad ""x"" line""#
+        );
+    }
+
+    #[test]
+    fn influx() {
+        let format = OutputFormat::Influx;
+        assert_eq!(
+            render(format, "front", &Reading::Valid(Distance::cm(48.27))),
+            "distance,sensor=front status=\"valid\",distance_cm=48.27 1760000000123000000"
+        );
+        assert_eq!(
+            render(format, "front", &Reading::OutOfRange(None)),
+            "distance,sensor=front status=\"out_of_range\" 1760000000123000000"
+        );
+        assert_eq!(
+            render(format, AWKWARD, &fault()),
+            r#"distance,sensor=front\ "left"\,\ a\\b status="fault",error="invalid configuration: bad \"x\" line" 1760000000123000000"#
+        );
+
+        let writer = ReadingWriter::new(Vec::new(), format).with_measurement("range finder,1").with_unit(LengthUnit::Mm);
+        assert_eq!(
+            writer.render("a=b", at(), &Reading::Valid(Distance::cm(48.27))),
+            r#"range\ finder\,1,sensor=a\=b status="valid",distance_mm=482.7 1760000000123000000"#
+        );
+    }
+
+    #[test]
+    fn units() {
+        let reading = Reading::Valid(Distance::cm(48.27));
+        let render = |unit| ReadingWriter::new(Vec::new(), OutputFormat::Csv).with_unit(unit).render("s", at(), &reading);
+        assert_eq!(render(LengthUnit::Mm), "1760000000.123,s,valid,482.7,");
+        assert_eq!(render(LengthUnit::Meter), "1760000000.123,s,valid,0.4827,");
+    }
+
+    #[test]
+    fn csv_header_written_once() {
+        let mut writer = ReadingWriter::new(Vec::new(), OutputFormat::Csv).with_unit(LengthUnit::Mm);
+        writer.write("front", at(), &Reading::Valid(Distance::cm(10.0))).unwrap();
+        writer.write("front", at(), &Reading::OutOfRange(None)).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "time,sensor,status,distance_mm,error\n\
+             1760000000.123,front,valid,100.0,\n\
+             1760000000.123,front,out_of_range,,\n"
+        );
+
+        let mut writer = ReadingWriter::new(Vec::new(), OutputFormat::JsonLines);
+        assert_eq!(writer.header(), None);
+        writer.write("front", at(), &Reading::OutOfRange(None)).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert_eq!("json".parse(), Ok(OutputFormat::JsonLines));
+        assert_eq!("jsonl".parse(), Ok(OutputFormat::JsonLines));
+        assert_eq!("csv".parse(), Ok(OutputFormat::Csv));
+        assert_eq!("influx".parse(), Ok(OutputFormat::Influx));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
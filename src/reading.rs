@@ -0,0 +1,112 @@
+use crate::{Distance, EchoBackend, HcSr04, HcSr04Error};
+use std::time::Duration;
+
+/// What came of one ping, with "nothing there" and "something very close"
+/// kept apart from actual failures.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Reading {
+    /// within the valid range
+    Valid(Distance),
+    /// closer than the minimum range, the distance is the raw reading
+    TooNear(Distance),
+    /// beyond the maximum range, or `None` if no echo came back at all
+    OutOfRange(Option<Distance>),
+    /// the GPIO lines, serial link or configuration failed
+    Fault(HcSr04Error),
+}
+
+impl Reading {
+    /// The distance of a valid reading.
+    pub fn distance(&self) -> Option<Distance> {
+        match self {
+            Reading::Valid(distance) => Some(*distance),
+            _ => None,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Reading::Valid(_))
+    }
+
+    pub fn is_fault(&self) -> bool {
+        matches!(self, Reading::Fault(_))
+    }
+
+    /// The outcome as a snake_case word, as serde names it.
+    pub fn status(&self) -> &'static str {
+        match self {
+            Reading::Valid(_) => "valid",
+            Reading::TooNear(_) => "too_near",
+            Reading::OutOfRange(_) => "out_of_range",
+            Reading::Fault(_) => "fault",
+        }
+    }
+}
+
+/// Sorts the errors of a `dist_*` call into outcomes.
+impl From<Result<Distance, HcSr04Error>> for Reading {
+    fn from(res: Result<Distance, HcSr04Error>) -> Self {
+        match res {
+            Ok(distance) => Reading::Valid(distance),
+            Err(HcSr04Error::OutOfRange { distance, min, .. }) if distance < min => Reading::TooNear(distance),
+            Err(HcSr04Error::OutOfRange { distance, .. }) => Reading::OutOfRange(Some(distance)),
+            Err(HcSr04Error::NoEchoStart { .. } | HcSr04Error::EchoNeverEnded { .. }) => Reading::OutOfRange(None),
+            Err(err) => Reading::Fault(err),
+        }
+    }
+}
+
+impl<B: EchoBackend> HcSr04<B> {
+    /// [`HcSr04::dist_cm`] as a [`Reading`].
+    pub fn reading(&mut self, timeout: Option<Duration>) -> Reading {
+        Reading::from(self.dist_cm(timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn sorts_results_into_outcomes() {
+        let timeout = Duration::from_millis(10);
+        let (min, max) = (Distance::cm(2.0), Some(Distance::cm(400.0)));
+        let gpio = gpio_cdev::Error::from(io::Error::from_raw_os_error(libc::EIO));
+
+        let cases = [
+            (Ok(Distance::cm(48.2)), "valid", Some(Distance::cm(48.2))),
+            (Err(HcSr04Error::NoEchoStart { timeout }), "out_of_range", None),
+            (Err(HcSr04Error::EchoNeverEnded { timeout }), "out_of_range", None),
+            (Err(HcSr04Error::OutOfRange { distance: Distance::cm(1.5), min, max }), "too_near", Some(Distance::cm(1.5))),
+            (Err(HcSr04Error::OutOfRange { distance: Distance::cm(512.0), min, max }), "out_of_range", Some(Distance::cm(512.0))),
+            (Err(HcSr04Error::Gpio(gpio)), "fault", None),
+            (Err(HcSr04Error::InvalidConfig("bad".to_string())), "fault", None),
+        ];
+
+        for (res, status, distance) in cases {
+            let case = format!("{:?}", res);
+            let reading = Reading::from(res);
+            assert_eq!(reading.status(), status, "{}", case);
+            let payload = match &reading {
+                Reading::Valid(distance) | Reading::TooNear(distance) => Some(*distance),
+                Reading::OutOfRange(distance) => *distance,
+                Reading::Fault(_) => None,
+            };
+            assert_eq!(payload, distance, "{}", case);
+            assert_eq!(reading.is_valid(), status == "valid", "{}", case);
+            assert_eq!(reading.is_fault(), status == "fault", "{}", case);
+        }
+    }
+
+    #[test]
+    fn faults_keep_their_error() {
+        let gpio = gpio_cdev::Error::from(io::Error::from_raw_os_error(libc::EIO));
+        match Reading::from(Err(HcSr04Error::Gpio(gpio))) {
+            Reading::Fault(err) => assert_eq!(err.kind(), "gpio"),
+            other => panic!("expected a fault, got {:?}", other),
+        }
+    }
+}
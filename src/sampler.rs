@@ -0,0 +1,318 @@
+use crate::{Distance, EchoBackend, Environment, HcSr04, HcSr04Error, SpeedOfSound};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::*;
+
+/// Datasheet minimum between two triggers, so one ping's echo dies down before the next.
+pub const MIN_MEASUREMENT_CYCLE: Duration = Duration::from_millis(60);
+
+/// How a [`Sampler`] hands samples over when the consumer falls behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Delivery {
+    /// only the newest sample is kept
+    Latest,
+    /// up to this many samples are queued, the oldest is dropped when full
+    Queue(usize),
+}
+
+/// One reading taken by a [`Sampler`]. `at` is left out when serialized, an
+/// `Instant` means nothing outside this process, which also rules out
+/// `Deserialize`.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Sample {
+    /// counts up from 0, gaps mean samples were dropped
+    pub seq: u64,
+    /// when the trigger was fired
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub at: Instant,
+    pub wall_clock: SystemTime,
+    /// distance in cm
+    pub result: Result<Distance, HcSr04Error>,
+}
+
+struct State {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+    dropped: u64,
+    stop: bool,
+    finished: bool,
+    speed_of_sound: Option<SpeedOfSound>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// signalled when a sample is pushed or the thread exits
+    ready: Condvar,
+    /// signalled to wake the sampling thread early
+    wake: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Owns an `HcSr04` on a dedicated thread that pings it at a fixed cadence.
+///
+/// Dropping the sampler stops the thread and waits for it; use [`Sampler::stop`]
+/// to get the sensor back instead.
+pub struct Sampler<B: EchoBackend + Send + 'static> {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<HcSr04<B>>>,
+    period: Duration,
+}
+
+impl<B: EchoBackend + Send + 'static> HcSr04<B> {
+    /// Moves the sensor onto a thread that measures every `period`, which is raised
+    /// to [`MIN_MEASUREMENT_CYCLE`] if shorter. Fails if the thread can't be spawned,
+    /// the sensor is dropped then.
+    pub fn spawn_sampler(self, period: Duration, delivery: Delivery) -> io::Result<Sampler<B>> {
+        let period = period.max(MIN_MEASUREMENT_CYCLE);
+        let capacity = match delivery {
+            Delivery::Latest => 1,
+            Delivery::Queue(capacity) => capacity.max(1),
+        };
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                samples: VecDeque::with_capacity(capacity),
+                capacity,
+                dropped: 0,
+                stop: false,
+                finished: false,
+                speed_of_sound: None,
+            }),
+            ready: Condvar::new(),
+            wake: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        let thread = thread::Builder::new()
+            .name("hc-sr04-sampler".to_string())
+            .spawn(move || run(self, &thread_shared, period))?;
+
+        Ok(Sampler {
+            shared,
+            thread: Some(thread),
+            period,
+        })
+    }
+}
+
+fn run<B: EchoBackend>(mut sensor: HcSr04<B>, shared: &Shared, period: Duration) -> HcSr04<B> {
+    let mut seq = 0;
+    let mut next = Instant::now();
+
+    loop {
+        {
+            let mut state = shared.lock();
+            while !state.stop {
+                let now = Instant::now();
+                if now >= next {
+                    break
+                }
+                state = shared.wake.wait_timeout(state, next - now)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+            }
+            if state.stop {
+                break
+            }
+            if let Some(speed_of_sound) = state.speed_of_sound.take() {
+                sensor.set_speed_of_sound(speed_of_sound);
+            }
+        }
+
+        let at = Instant::now();
+        let wall_clock = SystemTime::now();
+        let result = sensor.dist_cm(None);
+
+        // stay on the original grid, but don't try to catch up after a stall
+        next += period;
+        if next < Instant::now() {
+            next = Instant::now();
+        }
+
+        let mut state = shared.lock();
+        if state.samples.len() >= state.capacity {
+            state.samples.pop_front();
+            state.dropped += 1;
+        }
+        state.samples.push_back(Sample { seq, at, wall_clock, result });
+        seq += 1;
+        shared.ready.notify_all();
+    }
+
+    shared.lock().finished = true;
+    shared.ready.notify_all();
+    sensor
+}
+
+impl<B: EchoBackend + Send + 'static> Sampler<B> {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Oldest undelivered sample, if any.
+    pub fn try_recv(&self) -> Option<Sample> {
+        self.shared.lock().samples.pop_front()
+    }
+
+    /// Waits for the next sample. `None` once the sampling thread is gone.
+    pub fn recv(&self) -> Option<Sample> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(sample) = state.samples.pop_front() {
+                return Some(sample)
+            }
+            if state.finished {
+                return None
+            }
+            state = self.shared.ready.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Like [`Sampler::recv`], giving up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Sample> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(sample) = state.samples.pop_front() {
+                return Some(sample)
+            }
+            let now = Instant::now();
+            if state.finished || now >= deadline {
+                return None
+            }
+            state = self.shared.ready.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+        }
+    }
+
+    /// Newest undelivered sample, discarding any older ones.
+    pub fn latest(&self) -> Option<Sample> {
+        let mut state = self.shared.lock();
+        let latest = state.samples.pop_back();
+        state.samples.clear();
+        latest
+    }
+
+    /// Samples pushed out of the queue before they were received.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+
+    /// Applied before the next ping, see [`HcSr04::set_speed_of_sound`].
+    pub fn set_speed_of_sound(&self, speed_of_sound: SpeedOfSound) {
+        self.shared.lock().speed_of_sound = Some(speed_of_sound);
+    }
+
+    /// Applied before the next ping, see [`HcSr04::set_environment`].
+    pub fn set_environment(&self, env: Environment) {
+        self.set_speed_of_sound(SpeedOfSound::Air(env));
+    }
+
+    fn shutdown(&mut self) -> Option<HcSr04<B>> {
+        self.shared.lock().stop = true;
+        self.shared.wake.notify_all();
+        self.thread.take().and_then(|thread| thread.join().ok())
+    }
+
+    /// Stops the thread and hands the sensor back. Undelivered samples are lost.
+    pub fn stop(mut self) -> Option<HcSr04<B>> {
+        self.shutdown()
+    }
+}
+
+impl<B: EchoBackend + Send + 'static> Drop for Sampler<B> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockBackend, MockEcho};
+
+    fn sampler(delivery: Delivery) -> Sampler<MockBackend> {
+        let script = (1..100).map(|cm| MockEcho::from_distance(Distance::cm(cm as f64 * 10.0)));
+        HcSr04::with_backend(MockBackend::with_script(script), Distance::cm(2.0))
+            .spawn_sampler(Duration::ZERO, delivery)
+            .unwrap()
+    }
+
+    fn wait_for_drops(sampler: &Sampler<MockBackend>, dropped: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sampler.dropped() < dropped {
+            assert!(Instant::now() < deadline, "sampler never fell behind");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn latest_overwrites_older_samples() {
+        let sampler = sampler(Delivery::Latest);
+        assert_eq!(sampler.period(), MIN_MEASUREMENT_CYCLE);
+        wait_for_drops(&sampler, 2);
+
+        let seq = {
+            let state = sampler.shared.lock();
+            assert_eq!(state.samples.len(), 1);
+            // everything before the held sample was dropped
+            assert_eq!(state.samples[0].seq, state.dropped);
+            state.samples[0].seq
+        };
+        let sample = sampler.try_recv().unwrap();
+        assert!(sample.seq >= seq);
+        let cm = sample.result.unwrap().as_cm();
+        assert!((cm - 10.0 * (sample.seq + 1) as f64).abs() < 0.01, "{}", cm);
+    }
+
+    #[test]
+    fn queue_keeps_order() {
+        let sampler = sampler(Delivery::Queue(3));
+        wait_for_drops(&sampler, 1);
+
+        let first = {
+            let state = sampler.shared.lock();
+            assert_eq!(state.samples.len(), 3);
+            assert_eq!(state.samples[0].seq, state.dropped);
+            state.samples[0].seq
+        };
+        let seqs: Vec<u64> = (0..3).map(|_| sampler.recv().unwrap().seq).collect();
+        // the thread may have pushed out the oldest sample in the meantime
+        assert!(seqs[0] >= first);
+        assert_eq!(seqs, [seqs[0], seqs[0] + 1, seqs[0] + 2]);
+    }
+
+    #[test]
+    fn stop_joins_thread() {
+        let sampler = sampler(Delivery::Queue(10));
+        let first = sampler.recv().unwrap();
+        assert_eq!(first.seq, 0);
+        assert!((first.result.unwrap().as_cm() - 10.0).abs() < 0.01);
+
+        let shared = sampler.shared.clone();
+        let sensor = sampler.stop().unwrap();
+        assert!(shared.lock().finished);
+        // the thread's handle on the shared state is gone with it
+        assert_eq!(Arc::strong_count(&shared), 1);
+        assert!(sensor.backend().trigger_pulses() >= 1);
+    }
+
+    #[test]
+    fn drop_joins_thread() {
+        let sampler = sampler(Delivery::Latest);
+        assert!(sampler.recv().is_some());
+        let shared = sampler.shared.clone();
+        drop(sampler);
+        assert!(shared.lock().finished);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+}
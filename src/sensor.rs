@@ -0,0 +1,110 @@
+use crate::{Distance, EchoBackend, HcSr04, HcSr04Error, Reading, MIN_MEASUREMENT_CYCLE};
+
+/// What every distance sensor driver in this crate can do, for code that
+/// shouldn't care which module is attached.
+///
+/// In tests, an [`HcSr04`] on a [`MockBackend`](crate::MockBackend) stands in
+/// for any of them.
+pub trait RangeSensor {
+    /// Model name, e.g. `"HC-SR04"`.
+    fn name(&self) -> &str;
+
+    /// One reading in cm, with the driver's default timeout.
+    fn measure(&mut self) -> Result<Distance, HcSr04Error>;
+
+    /// Shortest distance that is reported, anything closer is out of range.
+    fn min_distance(&self) -> Distance;
+
+    /// Longest distance that is reported: the configured limit, or the
+    /// datasheet maximum if there is none.
+    fn max_distance(&self) -> Distance;
+
+    /// Full beam angle in degrees.
+    fn field_of_view(&self) -> f64;
+
+    /// Measurements per second the module is rated for.
+    fn update_rate(&self) -> f64;
+
+    /// [`RangeSensor::measure`] sorted into a [`Reading`].
+    fn read(&mut self) -> Reading {
+        Reading::from(self.measure())
+    }
+}
+
+impl<S: RangeSensor + ?Sized> RangeSensor for &mut S {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        (**self).measure()
+    }
+
+    fn min_distance(&self) -> Distance {
+        (**self).min_distance()
+    }
+
+    fn max_distance(&self) -> Distance {
+        (**self).max_distance()
+    }
+
+    fn field_of_view(&self) -> f64 {
+        (**self).field_of_view()
+    }
+
+    fn update_rate(&self) -> f64 {
+        (**self).update_rate()
+    }
+}
+
+impl<S: RangeSensor + ?Sized> RangeSensor for Box<S> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        (**self).measure()
+    }
+
+    fn min_distance(&self) -> Distance {
+        (**self).min_distance()
+    }
+
+    fn max_distance(&self) -> Distance {
+        (**self).max_distance()
+    }
+
+    fn field_of_view(&self) -> f64 {
+        (**self).field_of_view()
+    }
+
+    fn update_rate(&self) -> f64 {
+        (**self).update_rate()
+    }
+}
+
+impl<B: EchoBackend> RangeSensor for HcSr04<B> {
+    fn name(&self) -> &str {
+        "HC-SR04"
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(None)
+    }
+
+    fn min_distance(&self) -> Distance {
+        self.settings.dist_threshold
+    }
+
+    fn max_distance(&self) -> Distance {
+        self.settings.max_range.unwrap_or(Distance::cm(400.0))
+    }
+
+    fn field_of_view(&self) -> f64 {
+        15.0
+    }
+
+    fn update_rate(&self) -> f64 {
+        1.0 / MIN_MEASUREMENT_CYCLE.as_secs_f64()
+    }
+}
@@ -0,0 +1,155 @@
+use crate::backend::{CdevMultiBackend, MultiEchoBackend};
+use crate::builder::{check_range, Settings};
+use crate::{edges_to_cm, Distance, LengthUnit, EdgeEvent, Environment, HcSr04Builder, HcSr04Error, SpeedOfSound, TimingSource};
+use gpio_cdev::Chip;
+use std::time::*;
+
+/// Several HC-SR04 modules wired to one trigger GPIO, each with its own echo line.
+///
+/// One trigger pulse fires all of them; the echo lines are watched in the same
+/// poll set and each one yields its own distance. Modules that can see each other
+/// will pick up each other's pings, so point them apart.
+pub struct SharedTrigger<B: MultiEchoBackend = CdevMultiBackend> {
+    backend: B,
+    settings: Settings,
+    last_timing: Vec<Option<TimingSource>>,
+}
+
+impl SharedTrigger {
+    /// Uses the controller of the board's GPIO header, see
+    /// [`find_header_chip`](crate::find_header_chip). For consumers, bias and
+    /// the other options, see [`HcSr04Builder::build_shared_trigger`].
+    pub fn new(trig: u32, echoes: &[u32], dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        HcSr04Builder::new().min_range(dist_threshold).build_shared_trigger(trig, echoes)
+    }
+
+    pub fn with_chip(chip: Chip, trig: u32, echoes: &[u32], dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        HcSr04Builder::new().chip(chip).min_range(dist_threshold).build_shared_trigger(trig, echoes)
+    }
+}
+
+impl<B: MultiEchoBackend> SharedTrigger<B> {
+    /// Drives the sensors through any [`MultiEchoBackend`], e.g. a
+    /// [`MockMultiBackend`](crate::MockMultiBackend) in tests.
+    pub fn with_backend(backend: B, dist_threshold: Distance) -> Self {
+        Self::from_parts(backend, Settings::new(dist_threshold))
+    }
+
+    pub(crate) fn from_parts(backend: B, settings: Settings) -> Self {
+        let echo_count = backend.echo_count();
+        Self {
+            backend,
+            settings,
+            last_timing: vec![None; echo_count],
+        }
+    }
+
+    pub fn echo_count(&self) -> usize {
+        self.backend.echo_count()
+    }
+
+    pub fn min_range(&self) -> Distance {
+        self.settings.dist_threshold
+    }
+
+    /// Fails, keeping the old range, if `min` is negative or not below the
+    /// maximum range.
+    pub fn set_min_range(&mut self, min: Distance) -> Result<(), HcSr04Error> {
+        check_range(min, self.settings.max_range)?;
+        self.settings.dist_threshold = min;
+        Ok(())
+    }
+
+    pub fn max_range(&self) -> Option<Distance> {
+        self.settings.max_range
+    }
+
+    /// Readings further than this are rejected, `None` for no limit. Fails,
+    /// keeping the old range, unless `max` is above the minimum range.
+    pub fn set_max_range(&mut self, max: Option<Distance>) -> Result<(), HcSr04Error> {
+        check_range(self.settings.dist_threshold, max)?;
+        self.settings.max_range = max;
+        Ok(())
+    }
+
+    pub fn speed_of_sound(&self) -> SpeedOfSound {
+        self.settings.speed_of_sound
+    }
+
+    pub fn set_speed_of_sound(&mut self, speed_of_sound: SpeedOfSound) {
+        self.settings.speed_of_sound = speed_of_sound;
+    }
+
+    pub fn set_environment(&mut self, env: Environment) {
+        self.settings.speed_of_sound = SpeedOfSound::Air(env);
+    }
+
+    /// Clock used for each echo line's last successful reading.
+    pub fn last_timing_sources(&self) -> &[Option<TimingSource>] {
+        &self.last_timing
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    fn dist(&mut self, timeout: Option<Duration>) -> Result<Vec<Result<f64, HcSr04Error>>, HcSr04Error> {
+        self.backend.arm_echoes()?;
+
+        let backend = &mut self.backend;
+        self.settings.pulse_trigger(|high| backend.set_trigger(high))?;
+
+        let start_time = Instant::now();
+        let effective_timeout = self.settings.effective_timeout(timeout);
+
+        let count = self.backend.echo_count();
+        let mut edges: Vec<(Option<EdgeEvent>, Option<EdgeEvent>)> = vec![(None, None); count];
+        let mut pending = count;
+        while pending > 0 {
+            let remaining = effective_timeout.saturating_sub(start_time.elapsed());
+            let (index, event) = match self.backend.wait_any_edge(remaining)? {
+                Some(edge) => edge,
+                None => break
+            };
+            match &mut edges[index] {
+                (rising @ None, _) => *rising = Some(event),
+                (Some(_), falling @ None) => {
+                    *falling = Some(event);
+                    pending -= 1;
+                }
+                // that line is done, anything after is ringing or crosstalk
+                _ => (),
+            }
+        }
+
+        let results = edges.into_iter().enumerate().map(|(index, (rising, falling))| {
+            let res = edges_to_cm(&self.settings, start_time, effective_timeout, rising, falling);
+            self.last_timing[index] = res.as_ref().ok().map(|(_, timing)| *timing);
+            res.map(|(dist, _)| dist)
+        }).collect();
+        Ok(results)
+    }
+
+    /// One ping, one distance in m per echo line, in the order the lines were given.
+    /// The outer error is for failures that affect every line, like the trigger.
+    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Vec<Result<Distance, HcSr04Error>>, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(res.into_iter().map(|res| res.map(|res| Distance::cm(res).to(LengthUnit::Meter))).collect())
+    }
+
+    /// One ping, one distance in cm per echo line, see [`SharedTrigger::dist_meter`].
+    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Vec<Result<Distance, HcSr04Error>>, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(res.into_iter().map(|res| res.map(Distance::cm)).collect())
+    }
+
+    /// One ping, one distance in mm per echo line, see [`SharedTrigger::dist_meter`].
+    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Vec<Result<Distance, HcSr04Error>>, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(res.into_iter().map(|res| res.map(|res| Distance::cm(res).to(LengthUnit::Mm))).collect())
+    }
+}
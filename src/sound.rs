@@ -0,0 +1,150 @@
+use crate::{Velocity, SPEED_OF_SOUND};
+
+/// Standard atmosphere, Pa
+pub const STANDARD_PRESSURE_PA: f64 = 101_325.0;
+
+/// CO2 mole fraction assumed by the model
+const CO2_MOLE_FRACTION: f64 = 0.0004;
+
+/// Air conditions the speed of sound is computed from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Environment {
+    pub temperature_c: f64,
+    /// relative humidity in %, dry air if `None`
+    pub relative_humidity: Option<f64>,
+    /// absolute pressure in Pa, standard atmosphere if `None`
+    pub pressure_pa: Option<f64>,
+}
+
+impl Environment {
+    pub fn new(temperature_c: f64) -> Self {
+        Self {
+            temperature_c,
+            relative_humidity: None,
+            pressure_pa: None,
+        }
+    }
+
+    pub fn with_humidity(mut self, relative_humidity: f64) -> Self {
+        self.relative_humidity = Some(relative_humidity);
+        self
+    }
+
+    pub fn with_pressure(mut self, pressure_pa: f64) -> Self {
+        self.pressure_pa = Some(pressure_pa);
+        self
+    }
+
+    /// Speed of sound after Cramer (1993). Within 0.1% between 0 and 30 °C,
+    /// still within a few tenths of a percent at -20 and 50 °C.
+    pub fn speed_of_sound(&self) -> Velocity {
+        let t = self.temperature_c;
+        let p = self.pressure_pa.unwrap_or(STANDARD_PRESSURE_PA);
+        let h = self.relative_humidity.unwrap_or(0.0).clamp(0.0, 100.0) / 100.0;
+        let xc = CO2_MOLE_FRACTION;
+
+        // mole fraction of water vapour
+        let tk = t + 273.15;
+        let enhancement = 1.00062 + 3.14e-8 * p + 5.6e-7 * t * t;
+        let saturation = (1.2378847e-5 * tk * tk - 1.9121316e-2 * tk + 33.93711047 - 6.3431645e3 / tk).exp();
+        let xw = h * enhancement * saturation / p;
+
+        let meters_per_sec = 331.5024 + 0.603055 * t - 0.000528 * t * t
+            + (51.471935 + 0.1495874 * t - 0.000782 * t * t) * xw
+            + (-1.82e-7 + 3.73e-8 * t - 2.93e-10 * t * t) * p
+            + (-85.20931 - 0.228525 * t + 5.91e-5 * t * t) * xc
+            - 2.835149 * xw * xw
+            - 2.15e-13 * p * p
+            + 29.179762 * xc * xc
+            + 4.86e-4 * xw * p * xc;
+        Velocity::meters_per_sec(meters_per_sec)
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new(20.0)
+    }
+}
+
+/// How `HcSr04` turns echo time into distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SpeedOfSound {
+    /// the same speed whatever the weather
+    Fixed(Velocity),
+    /// computed from the air conditions, see [`Environment::speed_of_sound`]
+    Air(Environment),
+}
+
+impl SpeedOfSound {
+    pub fn velocity(&self) -> Velocity {
+        match self {
+            SpeedOfSound::Fixed(vel) => *vel,
+            SpeedOfSound::Air(env) => env.speed_of_sound(),
+        }
+    }
+
+    pub fn meters_per_sec(&self) -> f64 {
+        self.velocity().as_meters_per_sec()
+    }
+}
+
+/// 343 m/s, about right at 20 °C.
+impl Default for SpeedOfSound {
+    fn default() -> Self {
+        SpeedOfSound::Fixed(SPEED_OF_SOUND)
+    }
+}
+
+impl From<Velocity> for SpeedOfSound {
+    fn from(vel: Velocity) -> Self {
+        SpeedOfSound::Fixed(vel)
+    }
+}
+
+impl From<Environment> for SpeedOfSound {
+    fn from(env: Environment) -> Self {
+        SpeedOfSound::Air(env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speed(env: Environment) -> f64 {
+        env.speed_of_sound().as_meters_per_sec()
+    }
+
+    #[test]
+    fn reference_values() {
+        let dry_0 = speed(Environment::new(0.0));
+        assert!((dry_0 - 331.5).abs() < 0.1, "{}", dry_0);
+        let dry_20 = speed(Environment::new(20.0));
+        assert!((dry_20 - 343.4).abs() < 0.1, "{}", dry_20);
+        // Cramer gives 344.0 here; the textbook 343.2 is dry air by the ideal gas law
+        let humid_20 = speed(Environment::new(20.0).with_humidity(50.0));
+        assert!((humid_20 - 344.0).abs() < 0.1, "{}", humid_20);
+        assert!((humid_20 - 343.2).abs() < 1.0, "{}", humid_20);
+    }
+
+    #[test]
+    fn conditions_move_the_speed() {
+        let at = |temperature_c, humidity, pressure_pa| {
+            speed(Environment::new(temperature_c).with_humidity(humidity).with_pressure(pressure_pa))
+        };
+
+        assert!(at(10.0, 50.0, STANDARD_PRESSURE_PA) < at(20.0, 50.0, STANDARD_PRESSURE_PA));
+        // water vapour is lighter than air
+        assert!(at(20.0, 0.0, STANDARD_PRESSURE_PA) < at(20.0, 50.0, STANDARD_PRESSURE_PA));
+        assert!(at(20.0, 50.0, STANDARD_PRESSURE_PA) < at(20.0, 100.0, STANDARD_PRESSURE_PA));
+        assert_eq!(at(20.0, 150.0, STANDARD_PRESSURE_PA), at(20.0, 100.0, STANDARD_PRESSURE_PA));
+        // the same humidity is more vapour at lower pressure
+        assert!(at(20.0, 50.0, 110_000.0) < at(20.0, 50.0, STANDARD_PRESSURE_PA));
+        assert!(at(20.0, 50.0, STANDARD_PRESSURE_PA) < at(20.0, 50.0, 80_000.0));
+        assert_eq!(speed(Environment::new(20.0)), speed(Environment::new(20.0).with_pressure(STANDARD_PRESSURE_PA)));
+    }
+}
@@ -0,0 +1,577 @@
+use crate::backend::poll_with_timeout;
+use crate::builder::check_range;
+use crate::{Distance, HcSr04Error, LengthUnit, RangeSensor, MIN_MEASUREMENT_CYCLE};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::*;
+
+/// Header byte of an A02YYUW / JSN-SR04T frame.
+const FRAME_HEADER: u8 = 0xFF;
+/// US-100 command: measure and reply with the distance in mm, high byte first.
+const US100_DISTANCE: u8 = 0x55;
+/// US-100 command: reply with the temperature, offset by 45 °C.
+const US100_TEMPERATURE: u8 = 0x50;
+
+/// The US-100 answers within about 60 ms.
+const US100_TIMEOUT: Duration = Duration::from_millis(200);
+/// Frame modules send about every 100 ms, leave room for a torn frame.
+const FRAME_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Opens `path`, e.g. `/dev/serial0`, as a raw 9600 8N1 port, which is what all
+/// the supported modules speak.
+pub fn open_serial<P: AsRef<Path>>(path: P) -> Result<File, HcSr04Error> {
+    let path = path.as_ref();
+    let serial_err = |source| HcSr04Error::SerialOpen { path: path.to_path_buf(), source };
+
+    // O_NONBLOCK so the open doesn't wait for carrier detect, cleared again below
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(serial_err)?;
+    configure_raw(file.as_raw_fd()).map_err(serial_err)?;
+    Ok(file)
+}
+
+fn configure_raw(fd: RawFd) -> io::Result<()> {
+    let check = |ret: libc::c_int| match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        check(libc::tcgetattr(fd, &mut tio))?;
+        libc::cfmakeraw(&mut tio);
+        tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tio.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        // reads return whatever is there, waiting is done with poll
+        tio.c_cc[libc::VMIN] = 0;
+        tio.c_cc[libc::VTIME] = 0;
+        check(libc::cfsetispeed(&mut tio, libc::B9600))?;
+        check(libc::cfsetospeed(&mut tio, libc::B9600))?;
+        check(libc::tcsetattr(fd, libc::TCSANOW, &tio))?;
+        check(libc::tcflush(fd, libc::TCIOFLUSH))?;
+
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        check(flags)?;
+        check(libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK))
+    }
+}
+
+/// Whether the port has input within `timeout`. A failed poll is a serial
+/// error like any other on the port.
+fn readable<P: AsRawFd>(port: &P, timeout: Duration) -> Result<bool, HcSr04Error> {
+    poll_with_timeout(port.as_raw_fd(), timeout).map_err(|err| match err {
+        HcSr04Error::Poll(source) => HcSr04Error::Serial(source),
+        err => err,
+    })
+}
+
+/// Reads one byte, `None` if nothing arrived before `deadline`.
+fn read_byte<P: Read + AsRawFd>(port: &mut P, deadline: Instant) -> Result<Option<u8>, HcSr04Error> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !readable(port, remaining)? {
+            return Ok(None)
+        }
+        let mut byte = [0u8];
+        match port.read(&mut byte) {
+            Ok(1) => return Ok(Some(byte[0])),
+            // readable but empty means the other end hung up
+            Ok(_) => return Err(HcSr04Error::Serial(io::ErrorKind::UnexpectedEof.into())),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(HcSr04Error::Serial(err)),
+        }
+    }
+}
+
+/// Throws away anything already received, so the next byte is a reply to us.
+fn drain_input<P: Read + AsRawFd>(port: &mut P) -> Result<(), HcSr04Error> {
+    let mut buf = [0u8; 64];
+    while readable(port, Duration::ZERO)? {
+        match port.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(HcSr04Error::Serial(err)),
+        }
+    }
+    Ok(())
+}
+
+fn send<P: Write>(port: &mut P, command: u8) -> Result<(), HcSr04Error> {
+    port.write_all(&[command]).and_then(|_| port.flush()).map_err(HcSr04Error::Serial)
+}
+
+/// Checksum of an `0xFF H L SUM` frame: low byte of the sum of the first three.
+fn frame_checksum(high: u8, low: u8) -> u8 {
+    FRAME_HEADER.wrapping_add(high).wrapping_add(low)
+}
+
+/// Distance in cm from a reading in mm, checked against the valid range.
+fn checked_cm(mm: u16, min: Distance, max: Option<Distance>) -> Result<f64, HcSr04Error> {
+    let distance = Distance::mm(mm as f64);
+    if distance < min || max.is_some_and(|max| distance > max) {
+        return Err(HcSr04Error::OutOfRange { distance, min, max })
+    }
+    Ok(distance.as_cm())
+}
+
+/// US-100 with its jumper set to UART mode.
+///
+/// Every reading is requested with a command byte. The module compensates for
+/// temperature itself, and can report the temperature it used.
+pub struct Us100<P = File> {
+    port: P,
+    dist_threshold: Distance,
+    max_range: Option<Distance>,
+}
+
+impl Us100 {
+    /// Opens the serial port the module is wired to, see [`open_serial`].
+    pub fn open<T: AsRef<Path>>(path: T, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Ok(Self::with_port(open_serial(path)?, dist_threshold))
+    }
+}
+
+impl<P: Read + Write + AsRawFd> Us100<P> {
+    /// Talks over an already set up port, e.g. one end of a pty pair in tests.
+    pub fn with_port(port: P, dist_threshold: Distance) -> Self {
+        Self {
+            port,
+            dist_threshold,
+            max_range: Some(Distance::cm(450.0)),
+        }
+    }
+
+    pub fn min_range(&self) -> Distance {
+        self.dist_threshold
+    }
+
+    /// Fails, keeping the old range, if `min` is negative or not below the
+    /// maximum range.
+    pub fn set_min_range(&mut self, min: Distance) -> Result<(), HcSr04Error> {
+        check_range(min, self.max_range)?;
+        self.dist_threshold = min;
+        Ok(())
+    }
+
+    /// Longest reading that is still accepted, 4.5 m by default. Fails,
+    /// keeping the old range, unless `max_range` is above the minimum range.
+    pub fn set_max_range(&mut self, max_range: Option<Distance>) -> Result<(), HcSr04Error> {
+        check_range(self.dist_threshold, max_range)?;
+        self.max_range = max_range;
+        Ok(())
+    }
+
+    pub fn max_range(&self) -> Option<Distance> {
+        self.max_range
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn into_port(self) -> P {
+        self.port
+    }
+
+    /// Sends `command` and reads `N` reply bytes.
+    fn request<const N: usize>(&mut self, command: u8, timeout: Option<Duration>) -> Result<[u8; N], HcSr04Error> {
+        let timeout = timeout.unwrap_or(US100_TIMEOUT);
+        drain_input(&mut self.port)?;
+        send(&mut self.port, command)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut reply = [0u8; N];
+        for byte in reply.iter_mut() {
+            *byte = match read_byte(&mut self.port, deadline)? {
+                Some(byte) => byte,
+                None => return Err(HcSr04Error::NoResponse { timeout })
+            };
+        }
+        Ok(reply)
+    }
+
+    fn dist(&mut self, timeout: Option<Duration>) -> Result<f64, HcSr04Error> {
+        let reply = self.request::<2>(US100_DISTANCE, timeout)?;
+        checked_cm(u16::from_be_bytes(reply), self.dist_threshold, self.max_range)
+    }
+
+    /// `timeout` is how long to wait for the reply, 200 ms if `None`.
+    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res).to(LengthUnit::Meter))
+    }
+
+    /// See [`Us100::dist_meter`].
+    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res))
+    }
+
+    /// See [`Us100::dist_meter`].
+    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res).to(LengthUnit::Mm))
+    }
+
+    /// Temperature of the module's sensor in °C, whole degrees.
+    pub fn temperature(&mut self, timeout: Option<Duration>) -> Result<f64, HcSr04Error> {
+        let [raw] = self.request::<1>(US100_TEMPERATURE, timeout)?;
+        Ok(raw as f64 - 45.0)
+    }
+}
+
+/// When a [`FrameSensor`] sends its frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FrameOutput {
+    /// on its own, about every 100 ms; only the newest frame is used
+    Continuous,
+    /// once for each time this byte is sent to it, e.g. `0x55` for JSN-SR04T mode 3
+    OnRequest(u8),
+}
+
+/// Which module a [`FrameSensor`] is talking to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FrameModel {
+    /// UART auto output version, 3 cm to 4.5 m
+    A02yyuw,
+    /// with R27 set for mode 2 (continuous serial output), 20 cm to 6 m
+    JsnSr04t,
+}
+
+impl FrameModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameModel::A02yyuw => "A02YYUW",
+            FrameModel::JsnSr04t => "JSN-SR04T",
+        }
+    }
+
+    /// Datasheet maximum range.
+    pub fn max_range(&self) -> Distance {
+        match self {
+            FrameModel::A02yyuw => Distance::cm(450.0),
+            FrameModel::JsnSr04t => Distance::cm(600.0),
+        }
+    }
+
+    /// Datasheet beam angle in degrees.
+    pub fn field_of_view(&self) -> f64 {
+        match self {
+            FrameModel::A02yyuw => 60.0,
+            FrameModel::JsnSr04t => 75.0,
+        }
+    }
+}
+
+/// Modules that send `0xFF, high, low, checksum` frames with the distance in mm:
+/// the A02YYUW and the JSN-SR04T in its serial modes.
+///
+/// Bytes are read until four in a row make a frame with a matching checksum, so
+/// a frame torn by draining stale input or a `0xFF` data byte just costs a frame.
+pub struct FrameSensor<P = File> {
+    port: P,
+    model: FrameModel,
+    output: FrameOutput,
+    dist_threshold: Distance,
+    max_range: Option<Distance>,
+}
+
+impl FrameSensor {
+    pub fn a02yyuw<T: AsRef<Path>>(path: T, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Ok(Self::with_port(open_serial(path)?, FrameModel::A02yyuw, dist_threshold))
+    }
+
+    pub fn jsn_sr04t<T: AsRef<Path>>(path: T, dist_threshold: Distance) -> Result<Self, HcSr04Error> {
+        Ok(Self::with_port(open_serial(path)?, FrameModel::JsnSr04t, dist_threshold))
+    }
+}
+
+impl<P: Read + Write + AsRawFd> FrameSensor<P> {
+    /// Talks over an already set up port, e.g. one end of a pty pair in tests.
+    /// Starts out in [`FrameOutput::Continuous`], limited to the model's range.
+    pub fn with_port(port: P, model: FrameModel, dist_threshold: Distance) -> Self {
+        Self {
+            port,
+            model,
+            output: FrameOutput::Continuous,
+            dist_threshold,
+            max_range: Some(model.max_range()),
+        }
+    }
+
+    pub fn model(&self) -> FrameModel {
+        self.model
+    }
+
+    pub fn output(&self) -> FrameOutput {
+        self.output
+    }
+
+    pub fn set_output(&mut self, output: FrameOutput) {
+        self.output = output;
+    }
+
+    pub fn min_range(&self) -> Distance {
+        self.dist_threshold
+    }
+
+    /// Fails, keeping the old range, if `min` is negative or not below the
+    /// maximum range.
+    pub fn set_min_range(&mut self, min: Distance) -> Result<(), HcSr04Error> {
+        check_range(min, self.max_range)?;
+        self.dist_threshold = min;
+        Ok(())
+    }
+
+    /// Fails, keeping the old range, unless `max_range` is above the minimum
+    /// range.
+    pub fn set_max_range(&mut self, max_range: Option<Distance>) -> Result<(), HcSr04Error> {
+        check_range(self.dist_threshold, max_range)?;
+        self.max_range = max_range;
+        Ok(())
+    }
+
+    pub fn max_range(&self) -> Option<Distance> {
+        self.max_range
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn into_port(self) -> P {
+        self.port
+    }
+
+    /// Reads up to the next valid frame and returns its distance in mm.
+    fn read_frame(&mut self, timeout: Duration) -> Result<u16, HcSr04Error> {
+        let deadline = Instant::now() + timeout;
+        let mut window = [0u8; 4];
+        let mut filled = 0;
+        let mut bad_checksum = None;
+
+        loop {
+            let byte = match read_byte(&mut self.port, deadline)? {
+                Some(byte) => byte,
+                None => return Err(bad_checksum.unwrap_or(HcSr04Error::NoResponse { timeout }))
+            };
+            window.rotate_left(1);
+            window[3] = byte;
+            filled = (filled + 1).min(4);
+
+            if filled < 4 || window[0] != FRAME_HEADER {
+                continue
+            }
+            let [_, high, low, sum] = window;
+            let expected = frame_checksum(high, low);
+            if sum == expected {
+                return Ok(u16::from_be_bytes([high, low]))
+            }
+            bad_checksum = Some(HcSr04Error::Checksum { expected, actual: sum });
+        }
+    }
+
+    fn dist(&mut self, timeout: Option<Duration>) -> Result<f64, HcSr04Error> {
+        drain_input(&mut self.port)?;
+        if let FrameOutput::OnRequest(command) = self.output {
+            send(&mut self.port, command)?;
+        }
+        let mm = self.read_frame(timeout.unwrap_or(FRAME_TIMEOUT))?;
+        checked_cm(mm, self.dist_threshold, self.max_range)
+    }
+
+    /// `timeout` is how long to wait for a frame, 300 ms if `None`.
+    pub fn dist_meter(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res).to(LengthUnit::Meter))
+    }
+
+    /// See [`FrameSensor::dist_meter`].
+    pub fn dist_cm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res))
+    }
+
+    /// See [`FrameSensor::dist_meter`].
+    pub fn dist_mm(&mut self, timeout: Option<Duration>) -> Result<Distance, HcSr04Error> {
+        let res = self.dist(timeout)?;
+        Ok(Distance::cm(res).to(LengthUnit::Mm))
+    }
+}
+
+impl<P: Read + Write + AsRawFd> RangeSensor for Us100<P> {
+    fn name(&self) -> &str {
+        "US-100"
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(None)
+    }
+
+    fn min_distance(&self) -> Distance {
+        self.dist_threshold
+    }
+
+    fn max_distance(&self) -> Distance {
+        self.max_range.unwrap_or(Distance::cm(450.0))
+    }
+
+    fn field_of_view(&self) -> f64 {
+        15.0
+    }
+
+    fn update_rate(&self) -> f64 {
+        1.0 / MIN_MEASUREMENT_CYCLE.as_secs_f64()
+    }
+}
+
+impl<P: Read + Write + AsRawFd> RangeSensor for FrameSensor<P> {
+    fn name(&self) -> &str {
+        self.model.name()
+    }
+
+    fn measure(&mut self) -> Result<Distance, HcSr04Error> {
+        self.dist_cm(None)
+    }
+
+    fn min_distance(&self) -> Distance {
+        self.dist_threshold
+    }
+
+    fn max_distance(&self) -> Distance {
+        self.max_range.unwrap_or(self.model.max_range())
+    }
+
+    fn field_of_view(&self) -> f64 {
+        self.model.field_of_view()
+    }
+
+    /// Both modules send a frame about every 100 ms.
+    fn update_rate(&self) -> f64 {
+        10.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+    use std::thread::{self, JoinHandle};
+
+    /// A pty pair: the sensor end, set up like a real port, and the module end.
+    fn pty() -> (File, File) {
+        let (mut module, mut sensor) = (-1, -1);
+        let ret = unsafe { libc::openpty(&mut module, &mut sensor, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()) };
+        assert_eq!(ret, 0, "openpty: {}", io::Error::last_os_error());
+        configure_raw(sensor).unwrap();
+        unsafe { (File::from_raw_fd(sensor), File::from_raw_fd(module)) }
+    }
+
+    /// Plays the module: waits for each command and sends its reply.
+    fn module(mut port: File, script: Vec<(u8, Vec<u8>)>) -> JoinHandle<File> {
+        thread::spawn(move || {
+            for (command, reply) in script {
+                let mut byte = [0u8];
+                port.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], command);
+                port.write_all(&reply).unwrap();
+            }
+            port
+        })
+    }
+
+    #[test]
+    fn frame_with_ff_data_byte_after_torn_frame() {
+        let (port, module_port) = pty();
+        // the tail of a torn frame, then 511 mm, whose low byte and checksum are 0xFF
+        let module = module(module_port, vec![(0x55, vec![0xFF, 0x07, 0xFF, 0x01, 0xFF, 0xFF])]);
+        let mut sensor = FrameSensor::with_port(port, FrameModel::A02yyuw, Distance::cm(3.0));
+        sensor.set_output(FrameOutput::OnRequest(0x55));
+
+        assert_eq!(sensor.dist_mm(None).unwrap(), Distance::mm(511.0));
+        module.join().unwrap();
+    }
+
+    #[test]
+    fn frame_checksum_mismatch() {
+        let (port, module_port) = pty();
+        let module = module(module_port, vec![(0x55, vec![0xFF, 0x01, 0x00, 0x05])]);
+        let mut sensor = FrameSensor::with_port(port, FrameModel::JsnSr04t, Distance::cm(20.0));
+        sensor.set_output(FrameOutput::OnRequest(0x55));
+
+        match sensor.dist_cm(Some(Duration::from_millis(50))) {
+            Err(HcSr04Error::Checksum { expected: 0x00, actual: 0x05 }) => (),
+            other => panic!("expected a checksum error, got {:?}", other),
+        }
+        module.join().unwrap();
+    }
+
+    #[test]
+    fn us100_distance_and_temperature() {
+        let (port, module_port) = pty();
+        let module = module(module_port, vec![(US100_DISTANCE, vec![0x04, 0xD2]), (US100_TEMPERATURE, vec![45 + 22])]);
+        let mut sensor = Us100::with_port(port, Distance::cm(2.0));
+
+        assert_eq!(sensor.dist_mm(None).unwrap(), Distance::mm(1234.0));
+        assert_eq!(sensor.temperature(None).unwrap(), 22.0);
+        module.join().unwrap();
+    }
+
+    #[test]
+    fn us100_out_of_range() {
+        let (port, module_port) = pty();
+        let module = module(module_port, vec![(US100_DISTANCE, vec![0x00, 0x0A])]);
+        let mut sensor = Us100::with_port(port, Distance::cm(2.0));
+
+        assert!(matches!(sensor.dist_cm(None), Err(HcSr04Error::OutOfRange { .. })));
+        module.join().unwrap();
+    }
+
+    #[test]
+    fn range_setters_are_validated() {
+        let (port, _module_port) = pty();
+        let mut sensor = Us100::with_port(port, Distance::cm(2.0));
+        assert!(sensor.set_max_range(Some(Distance::cm(1.0))).is_err());
+        assert!(sensor.set_min_range(Distance::cm(500.0)).is_err());
+        assert_eq!(sensor.max_range(), Some(Distance::cm(450.0)));
+        assert_eq!(sensor.min_range(), Distance::cm(2.0));
+
+        let (port, _module_port) = pty();
+        let mut sensor = FrameSensor::with_port(port, FrameModel::JsnSr04t, Distance::cm(20.0));
+        assert!(sensor.set_min_range(Distance::cm(-1.0)).is_err());
+        sensor.set_max_range(None).unwrap();
+        sensor.set_min_range(Distance::cm(700.0)).unwrap();
+    }
+
+    #[test]
+    fn no_response() {
+        let (port, _module_port) = pty();
+        let mut sensor = Us100::with_port(port, Distance::cm(2.0));
+        let timeout = Duration::from_millis(30);
+        assert!(matches!(sensor.dist_cm(Some(timeout)), Err(HcSr04Error::NoResponse { timeout: t }) if t == timeout));
+
+        let (port, _module_port) = pty();
+        let mut sensor = FrameSensor::with_port(port, FrameModel::A02yyuw, Distance::cm(3.0));
+        assert!(matches!(sensor.dist_cm(Some(timeout)), Err(HcSr04Error::NoResponse { .. })));
+    }
+}
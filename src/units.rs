@@ -0,0 +1,502 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Unit a [`Distance`] was given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LengthUnit {
+    #[cfg_attr(feature = "serde", serde(rename = "mm"))]
+    Mm,
+    #[cfg_attr(feature = "serde", serde(rename = "cm"))]
+    Cm,
+    #[cfg_attr(feature = "serde", serde(rename = "m"))]
+    Meter,
+}
+
+impl LengthUnit {
+    const ALL: [LengthUnit; 3] = [LengthUnit::Mm, LengthUnit::Cm, LengthUnit::Meter];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            LengthUnit::Mm => "mm",
+            LengthUnit::Cm => "cm",
+            LengthUnit::Meter => "m",
+        }
+    }
+
+    /// How many of this unit make a meter.
+    fn per_base(&self) -> f64 {
+        match self {
+            LengthUnit::Mm => 1000.0,
+            LengthUnit::Cm => 100.0,
+            LengthUnit::Meter => 1.0,
+        }
+    }
+}
+
+/// Unit a [`Velocity`] was given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpeedUnit {
+    #[cfg_attr(feature = "serde", serde(rename = "mm/s"))]
+    MmPerSec,
+    #[cfg_attr(feature = "serde", serde(rename = "cm/s"))]
+    CmPerSec,
+    #[cfg_attr(feature = "serde", serde(rename = "m/s"))]
+    MetersPerSec,
+}
+
+impl SpeedUnit {
+    const ALL: [SpeedUnit; 3] = [SpeedUnit::MmPerSec, SpeedUnit::CmPerSec, SpeedUnit::MetersPerSec];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            SpeedUnit::MmPerSec => "mm/s",
+            SpeedUnit::CmPerSec => "cm/s",
+            SpeedUnit::MetersPerSec => "m/s",
+        }
+    }
+
+    /// How many of this unit make a meter per second.
+    fn per_base(&self) -> f64 {
+        match self {
+            SpeedUnit::MmPerSec => 1000.0,
+            SpeedUnit::CmPerSec => 100.0,
+            SpeedUnit::MetersPerSec => 1.0,
+        }
+    }
+}
+
+/// Moves `value` between two units given as "how many per base unit". All
+/// ratios are whole numbers, so this multiplies or divides by an exact factor,
+/// but the result is still rounded: `0.23cm` in m and back is `0.22999999999999998cm`.
+fn rescale(value: f64, from: f64, to: f64) -> f64 {
+    if to >= from {
+        value * (to / from)
+    } else {
+        value / (from / to)
+    }
+}
+
+/// A string that isn't a number followed by a known unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseQuantityError {
+    input: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseQuantityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid quantity {:?}: {}", self.input, self.reason)
+    }
+}
+
+impl Error for ParseQuantityError {}
+
+/// Everything `Distance` and `Velocity` have in common: the value is kept in the
+/// unit it was given in, comparisons and arithmetic convert as needed.
+macro_rules! quantity {
+    ($name:ident, $unit:ident, $base:ident) => {
+        impl $name {
+            pub const fn new(value: f64, unit: $unit) -> Self {
+                Self { value, unit }
+            }
+
+            /// The value in [`unit`](Self::unit), as given.
+            pub fn value(&self) -> f64 {
+                self.value
+            }
+
+            pub fn unit(&self) -> $unit {
+                self.unit
+            }
+
+            /// The value in `unit`.
+            pub fn value_in(&self, unit: $unit) -> f64 {
+                if unit == self.unit {
+                    return self.value
+                }
+                rescale(self.value, self.unit.per_base(), unit.per_base())
+            }
+
+            /// The same quantity expressed in `unit`.
+            pub fn to(self, unit: $unit) -> Self {
+                Self::new(self.value_in(unit), unit)
+            }
+
+            pub fn abs(self) -> Self {
+                Self::new(self.value.abs(), self.unit)
+            }
+
+            pub fn is_finite(&self) -> bool {
+                self.value.is_finite()
+            }
+
+            /// Whether `self` and `other` are at most `tolerance` apart, e.g. to
+            /// compare a converted value with the original.
+            pub fn approx_eq(&self, other: &Self, tolerance: Self) -> bool {
+                let diff = self.value_in($unit::$base) - other.value_in($unit::$base);
+                self == other || diff.abs() <= tolerance.value_in($unit::$base).abs()
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new(0.0, $unit::$base)
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.value_in($unit::$base) == other.value_in($unit::$base)
+            }
+        }
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                self.value_in($unit::$base).partial_cmp(&other.value_in($unit::$base))
+            }
+        }
+
+        /// The result is in the left-hand side's unit.
+        impl Add for $name {
+            type Output = Self;
+
+            fn add(self, rhs: Self) -> Self {
+                Self::new(self.value + rhs.value_in(self.unit), self.unit)
+            }
+        }
+
+        /// The result is in the left-hand side's unit.
+        impl Sub for $name {
+            type Output = Self;
+
+            fn sub(self, rhs: Self) -> Self {
+                Self::new(self.value - rhs.value_in(self.unit), self.unit)
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, rhs: Self) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, rhs: Self) {
+                *self = *self - rhs;
+            }
+        }
+
+        impl Neg for $name {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                Self::new(-self.value, self.unit)
+            }
+        }
+
+        impl Mul<f64> for $name {
+            type Output = Self;
+
+            fn mul(self, rhs: f64) -> Self {
+                Self::new(self.value * rhs, self.unit)
+            }
+        }
+
+        impl Mul<$name> for f64 {
+            type Output = $name;
+
+            fn mul(self, rhs: $name) -> $name {
+                rhs * self
+            }
+        }
+
+        impl Div<f64> for $name {
+            type Output = Self;
+
+            fn div(self, rhs: f64) -> Self {
+                Self::new(self.value / rhs, self.unit)
+            }
+        }
+
+        /// Ratio of two quantities, whatever their units.
+        impl Div for $name {
+            type Output = f64;
+
+            fn div(self, rhs: Self) -> f64 {
+                self.value / rhs.value_in(self.unit)
+            }
+        }
+
+        /// In the first item's unit, zero in the base unit if empty.
+        impl Sum for $name {
+            fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+                iter.reduce(|acc, item| acc + item).unwrap_or_default()
+            }
+        }
+
+        /// Value then unit suffix, e.g. `35.5cm`; precision applies to the value.
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Display::fmt(&self.value, f)?;
+                f.write_str(self.unit.suffix())
+            }
+        }
+
+        /// A number and a unit suffix, optionally separated by spaces, e.g. `"35cm"`.
+        impl FromStr for $name {
+            type Err = ParseQuantityError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let err = |reason| ParseQuantityError { input: s.to_string(), reason };
+                let trimmed = s.trim();
+
+                // longest suffix first, "mm" also ends in "m"
+                let mut units = $unit::ALL;
+                units.sort_by_key(|unit| std::cmp::Reverse(unit.suffix().len()));
+                let (number, unit) = match units.iter().find_map(|unit| {
+                    trimmed.strip_suffix(unit.suffix()).map(|number| (number, *unit))
+                }) {
+                    Some(split) => split,
+                    None => return Err(err("missing or unknown unit"))
+                };
+
+                match number.trim_end().parse::<f64>() {
+                    Ok(value) => Ok(Self::new(value, unit)),
+                    Err(_) => Err(err("not a number")),
+                }
+            }
+        }
+    };
+}
+
+/// A length that remembers the unit it was given in.
+///
+/// Equality and ordering hold across units, so `Distance::cm(1.0) == Distance::mm(10.0)`.
+/// They compare the exact values in m, so a conversion that rounds, like
+/// `0.23cm` to m and back, no longer compares equal to the original; use
+/// [`approx_eq`](Self::approx_eq) for that.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Distance {
+    value: f64,
+    unit: LengthUnit,
+}
+
+quantity!(Distance, LengthUnit, Meter);
+
+impl Distance {
+    pub const fn mm(value: f64) -> Self {
+        Self::new(value, LengthUnit::Mm)
+    }
+
+    pub const fn cm(value: f64) -> Self {
+        Self::new(value, LengthUnit::Cm)
+    }
+
+    pub const fn meters(value: f64) -> Self {
+        Self::new(value, LengthUnit::Meter)
+    }
+
+    pub fn as_mm(&self) -> f64 {
+        self.value_in(LengthUnit::Mm)
+    }
+
+    pub fn as_cm(&self) -> f64 {
+        self.value_in(LengthUnit::Cm)
+    }
+
+    pub fn as_meters(&self) -> f64 {
+        self.value_in(LengthUnit::Meter)
+    }
+}
+
+/// A speed that remembers the unit it was given in, see [`Distance`].
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Velocity {
+    value: f64,
+    unit: SpeedUnit,
+}
+
+quantity!(Velocity, SpeedUnit, MetersPerSec);
+
+impl Velocity {
+    pub const fn mm_per_sec(value: f64) -> Self {
+        Self::new(value, SpeedUnit::MmPerSec)
+    }
+
+    pub const fn cm_per_sec(value: f64) -> Self {
+        Self::new(value, SpeedUnit::CmPerSec)
+    }
+
+    pub const fn meters_per_sec(value: f64) -> Self {
+        Self::new(value, SpeedUnit::MetersPerSec)
+    }
+
+    pub fn as_mm_per_sec(&self) -> f64 {
+        self.value_in(SpeedUnit::MmPerSec)
+    }
+
+    pub fn as_cm_per_sec(&self) -> f64 {
+        self.value_in(SpeedUnit::CmPerSec)
+    }
+
+    pub fn as_meters_per_sec(&self) -> f64 {
+        self.value_in(SpeedUnit::MetersPerSec)
+    }
+}
+
+/// Distance covered in `rhs`, in the matching length unit.
+impl Mul<Duration> for Velocity {
+    type Output = Distance;
+
+    fn mul(self, rhs: Duration) -> Distance {
+        let unit = match self.unit {
+            SpeedUnit::MmPerSec => LengthUnit::Mm,
+            SpeedUnit::CmPerSec => LengthUnit::Cm,
+            SpeedUnit::MetersPerSec => LengthUnit::Meter,
+        };
+        Distance::new(self.value * rhs.as_secs_f64(), unit)
+    }
+}
+
+/// Average speed over `rhs`, in the matching speed unit.
+impl Div<Duration> for Distance {
+    type Output = Velocity;
+
+    fn div(self, rhs: Duration) -> Velocity {
+        let unit = match self.unit {
+            LengthUnit::Mm => SpeedUnit::MmPerSec,
+            LengthUnit::Cm => SpeedUnit::CmPerSec,
+            LengthUnit::Meter => SpeedUnit::MetersPerSec,
+        };
+        Velocity::new(self.value / rhs.as_secs_f64(), unit)
+    }
+}
+
+#[cfg(feature = "uom")]
+impl From<Distance> for uom::si::f64::Length {
+    fn from(dist: Distance) -> Self {
+        uom::si::f64::Length::new::<uom::si::length::meter>(dist.as_meters())
+    }
+}
+
+/// Comes out in meters, `uom` doesn't keep the unit it was given.
+#[cfg(feature = "uom")]
+impl From<uom::si::f64::Length> for Distance {
+    fn from(length: uom::si::f64::Length) -> Self {
+        Distance::meters(length.get::<uom::si::length::meter>())
+    }
+}
+
+#[cfg(feature = "uom")]
+impl From<Velocity> for uom::si::f64::Velocity {
+    fn from(vel: Velocity) -> Self {
+        uom::si::f64::Velocity::new::<uom::si::velocity::meter_per_second>(vel.as_meters_per_sec())
+    }
+}
+
+/// Comes out in m/s, see [`Distance`]'s conversion from `uom`.
+#[cfg(feature = "uom")]
+impl From<uom::si::f64::Velocity> for Velocity {
+    fn from(vel: uom::si::f64::Velocity) -> Self {
+        Velocity::meters_per_sec(vel.get::<uom::si::velocity::meter_per_second>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNITS: [LengthUnit; 3] = LengthUnit::ALL;
+
+    /// well above the rounding of a conversion, well below anything measurable
+    const ROUNDING: Distance = Distance::meters(1e-12);
+
+    #[test]
+    fn equal_across_units() {
+        assert_eq!(Distance::cm(1.0), Distance::mm(10.0));
+        assert_eq!(Distance::meters(4.0), Distance::cm(400.0));
+        assert_ne!(Distance::cm(1.0), Distance::mm(10.001));
+        assert_eq!(Velocity::meters_per_sec(343.0), Velocity::cm_per_sec(34300.0));
+
+        // equality is exact, so conversion rounding shows
+        assert_ne!(Distance::mm(1.4), Distance::cm(0.14));
+        assert!(Distance::mm(1.4).approx_eq(&Distance::cm(0.14), ROUNDING));
+        assert!(Distance::cm(0.09).to(LengthUnit::Mm).approx_eq(&Distance::cm(0.09), ROUNDING));
+        assert!(!Distance::cm(1.0).approx_eq(&Distance::mm(10.001), ROUNDING));
+        assert!(Distance::cm(1.0).approx_eq(&Distance::mm(10.001), Distance::mm(0.001)));
+        assert!(Distance::cm(f64::INFINITY).approx_eq(&Distance::mm(f64::INFINITY), ROUNDING));
+        assert!(!Distance::cm(f64::NAN).approx_eq(&Distance::cm(f64::NAN), ROUNDING));
+    }
+
+    #[test]
+    fn equality_is_transitive() {
+        // each within a tolerance of the next, but not of the one after
+        let (a, b, c) = (Distance::meters(1.0), Distance::meters(1.0 + 8e-13), Distance::meters(1.0 + 1.6e-12));
+        assert!(a.approx_eq(&b, ROUNDING) && b.approx_eq(&c, ROUNDING) && !a.approx_eq(&c, ROUNDING));
+        assert!(a != b && b != c && a < b && b < c);
+    }
+
+    #[test]
+    fn conversions_stay_close_to_the_original() {
+        // every two-decimal cm value up to 6 m, through every unit and back
+        for hundredths in 0..=60_000 {
+            let dist = Distance::cm(hundredths as f64 / 100.0);
+            for via in UNITS {
+                assert!(dist.to(via).approx_eq(&dist, ROUNDING), "{} in {:?}", dist, via);
+                for back in UNITS {
+                    assert!(dist.to(via).to(back).approx_eq(&dist, ROUNDING), "{} via {:?} to {:?}", dist, via, back);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ordering_across_units() {
+        assert!(Distance::mm(19.9) < Distance::cm(2.0));
+        assert!(Distance::meters(0.021) > Distance::cm(2.0));
+        assert_eq!(Distance::cm(f64::NAN).partial_cmp(&Distance::cm(1.0)), None);
+        assert_eq!(Distance::cm(f64::INFINITY), Distance::mm(f64::INFINITY));
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_left_unit() {
+        let sum = Distance::cm(2.0) + Distance::mm(5.0);
+        assert_eq!(sum.unit(), LengthUnit::Cm);
+        assert_eq!(sum, Distance::cm(2.5));
+        assert_eq!(Distance::meters(1.0) / Distance::cm(50.0), 2.0);
+        assert_eq!(Velocity::meters_per_sec(343.0) * Duration::from_millis(10), Distance::meters(3.43));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("35cm".parse::<Distance>().unwrap(), Distance::cm(35.0));
+        assert_eq!(" 1.5 m ".parse::<Distance>().unwrap().unit(), LengthUnit::Meter);
+        assert_eq!("12mm".parse::<Distance>().unwrap().unit(), LengthUnit::Mm);
+        assert_eq!("-2e1 mm".parse::<Distance>().unwrap(), Distance::cm(-2.0));
+        assert_eq!("343 m/s".parse::<Velocity>().unwrap(), Velocity::meters_per_sec(343.0));
+        assert_eq!("34300cm/s".parse::<Velocity>().unwrap().unit(), SpeedUnit::CmPerSec);
+
+        assert!("35".parse::<Distance>().is_err());
+        assert!("cm".parse::<Distance>().is_err());
+        assert!("35 in".parse::<Distance>().is_err());
+        assert!("3x5cm".parse::<Distance>().is_err());
+        assert!("35 m/s".parse::<Distance>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips_through_parse() {
+        for dist in [Distance::cm(48.27), Distance::mm(0.5), Distance::meters(3.999)] {
+            assert_eq!(dist.to_string().parse::<Distance>().unwrap(), dist);
+        }
+        assert_eq!(format!("{:.1}", Distance::cm(12.345)), "12.3cm");
+    }
+}
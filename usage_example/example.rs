@@ -1,16 +1,15 @@
-use hcsr04::*;
+use hcsr04_gpio_cdev::*;
 use std::{thread::sleep, time::Duration};
 const ECHO_PIN: u32 = 20; // GPIO20
 const TRIG_PIN: u32 = 21; // GPIO21
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut hcsr04 = HcSr04::new(TRIG_PIN, ECHO_PIN)?;
-    // let timeout = range_to_timeout(DistanceUnit::Cm(400.0))?;
+    let mut hcsr04 = HcSr04::new(TRIG_PIN, ECHO_PIN, Distance::cm(2.0))?;
+    // let timeout = range_to_timeout(Distance::cm(400.0))?;
 
     loop {
         let distance = hcsr04.dist_cm(None)?;
-        println!("Distance: {:05.2}cm", distance.to_val());
+        println!("Distance: {:05.2}", distance);
         sleep(Duration::from_secs_f32(0.2));
     }
-    Ok(())
 }